@@ -7,13 +7,24 @@ import numpy as np
 import matplotlib.pyplot as plt
 
 
+def read_csv(path):
+    with open(path) as f:
+        rows = list(csv.reader(f))
+
+    # Skip the header row containing the column names, if present.
+    try:
+        float(rows[0][0])
+    except ValueError:
+        rows = rows[1:]
+
+    return [[float(j) for j in i] for i in rows]
+
+
 def main(file1, file2):
-    with open(file1) as f:
-        data1 = [[float(j) for j in i] for i in list(csv.reader(f))]
+    data1 = read_csv(file1)
 
     if file2 is not None:
-        with open(file2) as f:
-            data2 = [[float(j) for j in i] for i in list(csv.reader(f))]
+        data2 = read_csv(file2)
 
     cols = list(zip(*data1))
     x = cols[0]
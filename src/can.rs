@@ -1,57 +1,191 @@
 //! Protocol implementation for the CAN bus / ISO 15765
 
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use log::{debug, warn};
+use log::{debug, error, trace, warn};
 use socketcan;
 
 use crate::error::*;
 use crate::misc::*;
 use crate::obd2::*;
 
+/// Error class bits of CAN error frames, see linux/can/error.h
+const CAN_ERR_CLASSES: [(u32, &str); 9] = [
+    (0x001, "TX timeout"),
+    (0x002, "lost arbitration"),
+    (0x004, "controller problem"),
+    (0x008, "protocol violation"),
+    (0x010, "transceiver status"),
+    (0x020, "no ACK received"),
+    (0x040, "bus off"),
+    (0x080, "bus error"),
+    (0x100, "controller restarted"),
+];
+
+/// Error class of bus-off error frames
+const CAN_ERR_BUSOFF: u32 = 0x040;
+
+/// Error class of error frames sent after the controller was restarted
+const CAN_ERR_RESTARTED: u32 = 0x100;
+
+/// Time after which a query without a complete response fails
+const RESPONSE_TIMEOUT_MILLIS: u64 = 2000;
+
+/// Default CAN network interface
+const DEFAULT_INTERFACE: &str = "can0";
+
+/// CAN network interface to use instead of the default, see [set_interface]
+static INTERFACE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Whether the CAN interface was brought up and not brought down since
+static INTERFACE_UP: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Set the CAN network interface to use instead of `can0`.
+ */
+pub fn set_interface(name: &str) {
+    *INTERFACE.lock().unwrap() = Some(String::from(name));
+}
+
+/**
+ * Return the CAN network interface to use.
+ */
+fn interface() -> String {
+    INTERFACE
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_INTERFACE))
+}
+
 /// Protocol for talking to the vehicle via the CAN bus.
 pub struct CanBus {
     /// CAN bus socket
     pub socket: socketcan::CANSocket,
+    bit_rate: u64,
+    supported_pids: Option<Vec<u8>>,
+    /// ID of the ECU that answered the last query
+    responder: Option<u32>,
 }
 
 impl CanBus {
     /**
-     * Initialize the CAN protocol on the `can0` interface. This method expects
-     * the `can0` network interface to not currently exist, and brings it up via
+     * Initialize the CAN protocol on the `can0` interface, or the one set via
+     * [set_interface]. This method expects the network interface to not
+     * currently be up, and brings it up via
      * the `ip` command with the given bit rate, or 500kpbs if none is given.
      *
      * The [Drop] trait is implemented to ensure the network interface is
-     * brought down again on termination.
+     * brought down again on termination. SIGINT and SIGTERM abort pending
+     * queries, so the interface is brought down in that case as well.
      */
     pub fn init(bit_rate: Option<u64>) -> Result<Self, Error> {
-        run_cmd_as_root(format!(
-            "ip link set can0 up type can bitrate {}",
-            bit_rate.unwrap_or(500000)
-        ))?;
+        let bit_rate = bit_rate.unwrap_or(500000);
+
+        running_flag();
+
+        Self::bring_up(bit_rate)?;
+        INTERFACE_UP.store(true, Ordering::SeqCst);
+
+        let socket = Self::open_socket()?;
+
+        Ok(Self {
+            socket,
+            bit_rate,
+            supported_pids: None,
+            responder: None,
+        })
+    }
+
+    /**
+     * Bring up the CAN interface with the given bit rate.
+     */
+    fn bring_up(bit_rate: u64) -> Result<(), Error> {
+        let interface = interface();
+        let bit_rate = bit_rate.to_string();
+        run_cmd_as_root(
+            "ip",
+            &[
+                "link", "set", &interface, "up", "type", "can", "bitrate", &bit_rate,
+            ],
+        )
+    }
 
-        let socket = socketcan::CANSocket::open("can0")?;
+    /**
+     * Open the socket on the CAN interface, including error frames.
+     */
+    fn open_socket() -> Result<socketcan::CANSocket, Error> {
+        let socket = socketcan::CANSocket::open(&interface())?;
         socket.set_read_timeout(Duration::from_millis(500))?;
         socket.set_write_timeout(Duration::from_millis(500))?;
+        socket.error_filter_accept_all()?;
+
+        Ok(socket)
+    }
 
-        Ok(Self { socket })
+    /**
+     * Cycle the CAN interface to recover from the bus-off state, and
+     * reopen the socket.
+     */
+    fn restart(&mut self) -> Result<(), Error> {
+        run_cmd_as_root("ip", &["link", "set", &interface(), "down"])?;
+        Self::bring_up(self.bit_rate)?;
+
+        self.socket = Self::open_socket()?;
+
+        Ok(())
+    }
+
+    /**
+     * Log the given error frame, and attempt to recover if the controller
+     * went bus-off. Returns an error describing the problem if the controller
+     * went bus-off or was restarted, since the current transaction is lost in
+     * that case. Other errors, such as lost arbitration or a single bus
+     * error, are transient and only logged.
+     */
+    pub fn handle_error_frame(&mut self, frame: &socketcan::CANFrame) -> Option<Error> {
+        let classes: Vec<&str> = CAN_ERR_CLASSES
+            .iter()
+            .filter(|(class, _)| frame.err() & class != 0)
+            .map(|(_, name)| *name)
+            .collect();
+
+        if frame.err() & (CAN_ERR_BUSOFF | CAN_ERR_RESTARTED) == 0 {
+            warn!("Ignoring transient CAN error ({}).", classes.join(", "));
+            return None;
+        }
+
+        error!(
+            "CAN error ({}), check wiring, termination and bit rate.",
+            classes.join(", ")
+        );
+
+        if frame.err() & CAN_ERR_BUSOFF != 0 {
+            warn!("CAN controller is bus-off, restarting interface.");
+
+            if let Err(e) = self.restart() {
+                return Some(Error::connection_lost(format!(
+                    "Failed to recover from bus-off: {}",
+                    e
+                )));
+            }
+        }
+
+        Some(Error::connection_lost(format!(
+            "CAN error: {}",
+            classes.join(", ")
+        )))
     }
 
     /**
      * Run a crude car simulator using the given bit rate.
      */
     pub fn run_simulator(bit_rate: u64) -> Result<(), Error> {
-        let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
-
-        ctrlc::set_handler(move || {
-            r.store(false, Ordering::SeqCst);
-        })
-        .unwrap();
+        let running = running_flag();
 
-        let can_bus = Self::init(Some(bit_rate))?;
+        let mut can_bus = Self::init(Some(bit_rate))?;
 
         while running.load(Ordering::SeqCst) {
             let frame = match can_bus.socket.read_frame() {
@@ -61,14 +195,20 @@ impl CanBus {
                 Ok(f) => f,
             };
 
-            debug!("RECV {:02X}", frame);
+            if frame.is_error() {
+                // The error is already logged, just keep serving requests.
+                can_bus.handle_error_frame(&frame);
+                continue;
+            }
+
+            trace!(target: FRAME_LOG_TARGET, "RECV {:02X}", frame);
 
             match frame.data()[1] {
                 0x03 | 0x07 => {
                     // send some unrelated message to keep the receiver on its toes
                     let response =
                         socketcan::CANFrame::new(0x484, &[0x02, 0x00, 0x42], false, false)?;
-                    debug!("SEND {:02X}", response);
+                    trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                     can_bus.socket.write_frame(&response)?;
 
                     let service = 0x40 + frame.data()[1];
@@ -78,12 +218,12 @@ impl CanBus {
                         false,
                         false,
                     )?;
-                    debug!("SEND {:02X}", response);
+                    trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                     can_bus.socket.write_frame(&response)?;
                 }
                 0x04 => {
                     let response = socketcan::CANFrame::new(0x7e8, &[0x01, 0x44], false, false)?;
-                    debug!("SEND {:02X}", response);
+                    trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                     can_bus.socket.write_frame(&response)?;
                 }
                 0x09 => match frame.data()[2] {
@@ -92,7 +232,7 @@ impl CanBus {
                         frame1.extend("VIN".as_bytes());
 
                         let response = socketcan::CANFrame::new(0x7e8, &frame1, false, false)?;
-                        debug!("SEND {:02X}", response);
+                        trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                         can_bus.socket.write_frame(&response)?;
 
                         // Handle only a single FC frame.
@@ -106,14 +246,14 @@ impl CanBus {
                         frame2.extend("VINVINV".as_bytes());
 
                         let response = socketcan::CANFrame::new(0x7e8, &frame2, false, false)?;
-                        debug!("SEND {:02X}", response);
+                        trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                         can_bus.socket.write_frame(&response)?;
 
                         let mut frame3: Vec<u8> = vec![0x21];
                         frame3.extend("IN".as_bytes());
 
                         let response = socketcan::CANFrame::new(0x7e8, &frame3, false, false)?;
-                        debug!("SEND {:02X}", response);
+                        trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", response);
                         can_bus.socket.write_frame(&response)?;
                     }
                     _ => {
@@ -132,98 +272,535 @@ impl CanBus {
 
 impl Drop for CanBus {
     fn drop(&mut self) {
-        if let Err(e) = run_cmd_as_root("ip link set can0 down") {
+        shutdown_interface();
+    }
+}
+
+/**
+ * Pretend the CAN interface was brought up, so [shutdown_interface] brings it
+ * down again.
+ */
+#[cfg(test)]
+pub fn mark_interface_up() {
+    INTERFACE_UP.store(true, Ordering::SeqCst);
+}
+
+/**
+ * Return whether the CAN interface was brought up and not brought down since.
+ */
+#[cfg(test)]
+pub fn interface_up() -> bool {
+    INTERFACE_UP.load(Ordering::SeqCst)
+}
+
+/**
+ * Bring the CAN interface down if it was brought up by [CanBus::init] and
+ * is still up. Besides dropping the [CanBus], this is called on exit as a
+ * last resort, e.g. after a panic.
+ */
+pub fn shutdown_interface() {
+    if INTERFACE_UP.swap(false, Ordering::SeqCst) {
+        if let Err(e) = run_cmd_as_root("ip", &["link", "set", &interface(), "down"]) {
             warn!("Failed to shut down CAN interface: {}", e);
         }
     }
 }
 
-impl Obd2Protocol for CanBus {
-    fn obd_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error> {
-        let mut data: Vec<u8> = vec![1 + args.len() as u8, service];
-        data.extend(args);
-        data.extend(vec![0xcc; 8 - data.len()]);
-
-        let query = socketcan::CANFrame::new(0x7df, &data, false, false)?;
-        debug!("SEND {:02X}", query);
-        self.socket.write_frame_insist(&query)?;
+/// Result of processing a frame with [IsoTpReceiver::push]
+#[derive(Debug, PartialEq)]
+enum IsoTpStatus {
+    /// The frame was sent by another ECU and ignored
+    Ignored,
+    /// More frames are expected once flow control was sent to the given ID
+    FlowControl(u32),
+    /// More consecutive frames are expected
+    Incomplete,
+    /// The message is complete
+    Complete,
+}
 
-        let mut response = Vec::new();
-        let mut length: Option<usize> = None;
+/// Reassembly of a CAN-TP (ISO 15765-2) message from the frames of a single
+/// ECU.
+#[derive(Debug, Default)]
+struct IsoTpReceiver {
+    source: Option<u32>,
+    length: Option<usize>,
+    data: Vec<u8>,
+}
 
-        let start = SystemTime::now();
-        loop {
-            if start.elapsed().unwrap().as_millis() > 2000 {
-                return Err(Error::new("Timed out waiting for response."));
+impl IsoTpReceiver {
+    /**
+     * Process the data of a frame received from the given ID.
+     *
+     * Functional requests may be answered by multiple ECUs, only the frames
+     * of the first one to respond are used.
+     */
+    fn push(&mut self, id: u32, frame: &[u8]) -> Result<IsoTpStatus, Error> {
+        match self.source {
+            Some(source) if source != id => {
+                debug!("Ignoring response from ECU 0x{:03x}.", id);
+                return Ok(IsoTpStatus::Ignored);
             }
-
-            let frame = self.socket.read_frame()?;
-            if !(frame.id() >= 0x7e8 && frame.id() <= 0x7ef) {
-                continue;
+            _ => {
+                self.source = Some(id);
             }
+        }
 
-            debug!("RECV {:02X}", frame);
+        if frame.is_empty() {
+            return Err(Error::new("Empty CAN-TP frame."));
+        }
 
-            let frame_type = frame.data()[0] >> 4;
-            match frame_type {
-                0x00 => {
-                    // single frame
-                    if length.is_some() {
-                        return Err(Error::new("Unexpected CAN-TP message."));
-                    }
+        match frame[0] >> 4 {
+            0x00 => {
+                // single frame
+                if self.length.is_some() {
+                    return Err(Error::new("Unexpected CAN-TP message."));
+                }
 
-                    length = Some(frame.data()[0] as usize);
+                // The length is in the low nibble, and has to fit into the
+                // frame.
+                let len = (frame[0] & 0x0f) as usize;
+                if len == 0 || len > 7 || len > frame.len() - 1 {
+                    return Err(Error::new("Invalid CAN-TP single frame length."));
+                }
+
+                self.length = Some(len);
+                self.data.extend(&frame[1..]);
 
-                    response.extend(&frame.data()[1..]);
-                    break;
+                Ok(IsoTpStatus::Complete)
+            }
+            0x01 => {
+                // first multi-frame message
+                if self.length.is_some() || frame.len() < 2 {
+                    return Err(Error::new("Unexpected CAN-TP message."));
                 }
-                0x01 => {
-                    // first multi-frame message
-                    if length.is_some() {
-                        return Err(Error::new("Unexpected CAN-TP message."));
-                    }
 
-                    length =
-                        Some(((frame.data()[0] as usize & 0xf) << 8) + frame.data()[1] as usize);
-                    response.extend(&frame.data()[2..]);
+                let len = ((frame[0] as usize & 0xf) << 8) + frame[1] as usize;
+                self.length = Some(len);
+                self.data.extend(&frame[2..]);
 
-                    // acknowledge, instruct sender to send the rest without
-                    // waiting for further flow control messages
-                    let msg = vec![0x30, 0x00, 0xff, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc];
-                    let flow = socketcan::CANFrame::new(0x7df, &msg, false, false)?;
-                    debug!("SEND {:02X}", flow);
-                    self.socket.write_frame_insist(&flow)?;
+                // Short messages should be sent as a single frame, but if the
+                // first frame already contains all of the data, there is
+                // nothing left to wait for.
+                if len <= self.data.len() {
+                    return Ok(IsoTpStatus::Complete);
                 }
-                0x02 => {
-                    // consecutive multi-frame message
-                    if length.is_none() {
+
+                // Flow control is addressed to the responding ECU only
+                // (0x7e8 -> 0x7e0), so other ECUs don't mistake it for their
+                // own.
+                Ok(IsoTpStatus::FlowControl(id - 8))
+            }
+            0x02 => {
+                // consecutive multi-frame message
+                let length = match self.length {
+                    Some(l) => l,
+                    None => {
                         return Err(Error::new("Unexpected CAN-TP message."));
                     }
+                };
 
-                    // TODO: check index?
+                // TODO: check index?
 
-                    response.extend(&frame.data()[1..]);
+                self.data.extend(&frame[1..]);
 
-                    if response.len() >= length.unwrap() {
-                        break;
-                    }
+                if self.data.len() >= length {
+                    Ok(IsoTpStatus::Complete)
+                } else {
+                    Ok(IsoTpStatus::Incomplete)
                 }
-                _ => {
-                    return Err(Error::new("Unexpected CAN-TP message."));
+            }
+            _ => Err(Error::new("Unexpected CAN-TP message.")),
+        }
+    }
+
+    /**
+     * Return the complete message, without the padding of the last frame.
+     */
+    fn finish(mut self) -> Vec<u8> {
+        if let Some(length) = self.length {
+            self.data.truncate(length);
+        }
+
+        self.data
+    }
+}
+
+/**
+ * Return whether the given socket read error only means that the next frame
+ * didn't arrive within the read timeout, as opposed to the socket failing.
+ */
+fn frame_late(error: &std::io::Error) -> bool {
+    error.kind() == std::io::ErrorKind::WouldBlock || error.kind() == std::io::ErrorKind::TimedOut
+}
+
+/// Sending and receiving of CAN frames, so the CAN-TP handling can be used
+/// without a socket.
+trait FrameIo {
+    /**
+     * Return the next frame, or None if none arrived within the read timeout.
+     * Error frames are handled here, and returned as errors if the current
+     * transaction is lost.
+     */
+    fn next_frame(&mut self) -> Result<Option<socketcan::CANFrame>, Error>;
+
+    /**
+     * Send the given frame.
+     */
+    fn send_frame(&mut self, frame: &socketcan::CANFrame) -> Result<(), Error>;
+}
+
+impl FrameIo for CanBus {
+    fn next_frame(&mut self) -> Result<Option<socketcan::CANFrame>, Error> {
+        let frame = match self.socket.read_frame() {
+            Ok(f) => f,
+            Err(e) if frame_late(&e) => {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(Error::connection_lost(e.to_string()));
+            }
+        };
+
+        if frame.is_error() {
+            return match self.handle_error_frame(&frame) {
+                Some(e) => Err(e),
+                None => Ok(None),
+            };
+        }
+
+        Ok(Some(frame))
+    }
+
+    fn send_frame(&mut self, frame: &socketcan::CANFrame) -> Result<(), Error> {
+        trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", frame);
+        self.socket
+            .write_frame_insist(frame)
+            .map_err(|e| Error::connection_lost(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/**
+ * Receive the CAN-TP response to a query using the given receiver,
+ * acknowledging multi-frame messages, until it is complete or the given time
+ * has passed. Returns the ID of the responding ECU and the message.
+ */
+fn receive_response<T: FrameIo>(
+    io: &mut T,
+    mut receiver: IsoTpReceiver,
+    timeout: Duration,
+) -> Result<(u32, Vec<u8>), Error> {
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > timeout {
+            // No response is the usual answer to unsupported PIDs, so this
+            // doesn't mean the connection was lost.
+            return Err(Error::new("Timed out waiting for response."));
+        }
+
+        if !running_flag().load(Ordering::SeqCst) {
+            return Err(Error::new("Interrupted."));
+        }
+
+        // A read timeout only means a single frame is late, keep waiting
+        // until the overall deadline above is reached.
+        let frame = match io.next_frame()? {
+            Some(f) => f,
+            None => {
+                continue;
+            }
+        };
+
+        if !(frame.id() >= 0x7e8 && frame.id() <= 0x7ef) {
+            continue;
+        }
+
+        trace!(target: FRAME_LOG_TARGET, "RECV {:02X}", frame);
+
+        match receiver.push(frame.id(), frame.data())? {
+            IsoTpStatus::Complete => {
+                return Ok((frame.id(), receiver.finish()));
+            }
+            IsoTpStatus::FlowControl(id) => {
+                // acknowledge, instruct sender to send the rest without
+                // waiting for further flow control messages.
+                let msg = vec![0x30, 0x00, 0xff, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc];
+                io.send_frame(&socketcan::CANFrame::new(id, &msg, false, false)?)?;
+            }
+            IsoTpStatus::Ignored | IsoTpStatus::Incomplete => {}
+        }
+    }
+}
+
+/**
+ * Check that the given response matches the service and args/PIDs of the
+ * request, and return the remaining value.
+ */
+fn strip_response(response: Vec<u8>, service: u8, args: &[u8]) -> Result<Vec<u8>, Error> {
+    if response.len() < 1 + args.len() {
+        return Err(Error::new("Response shorter than request."));
+    }
+
+    if response[0] != service + 0x40 {
+        return Err(Error::new("Service identifier of response did not match."));
+    }
+
+    if &response[1..(1 + args.len())] != args {
+        return Err(Error::new("Arguments/PIDs did not match."));
+    }
+
+    Ok(response[(1 + args.len())..].to_vec())
+}
+
+impl CanBus {
+    /**
+     * Send a query to the given ID, and receive the response using the given
+     * receiver, see [Obd2Protocol::obd_query].
+     */
+    fn query(
+        &mut self,
+        id: u32,
+        receiver: IsoTpReceiver,
+        service: u8,
+        args: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut data: Vec<u8> = vec![1 + args.len() as u8, service];
+        data.extend(args);
+        data.extend(vec![0xcc; 8 - data.len()]);
+
+        self.send_frame(&socketcan::CANFrame::new(id, &data, false, false)?)?;
+        let start = Instant::now();
+
+        let timeout = Duration::from_millis(RESPONSE_TIMEOUT_MILLIS);
+        let (responder, response) = receive_response(self, receiver, timeout)?;
+        self.responder = Some(responder);
+
+        record_latency(start.elapsed());
+
+        strip_response(response, service, args)
+    }
+}
+
+impl Obd2Protocol for CanBus {
+    fn obd_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error> {
+        self.query(0x7df, IsoTpReceiver::default(), service, args)
+    }
+
+    fn ecu_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error> {
+        // Find out which ECU answers using a harmless query first.
+        let responder = match self.responder {
+            Some(id) => id,
+            None => {
+                self.obd_query(0x01, &[0x00])?;
+                self.responder
+                    .ok_or_else(|| Error::new("No ECU responded."))?
+            }
+        };
+
+        // Physical requests are addressed to the ECU's response ID - 8
+        // (0x7e8 -> 0x7e0), and only answered by that ECU.
+        let receiver = IsoTpReceiver {
+            source: Some(responder),
+            ..Default::default()
+        };
+
+        self.query(responder - 8, receiver, service, args)
+    }
+
+    fn supported_pids_cache(&mut self) -> &mut Option<Vec<u8>> {
+        &mut self.supported_pids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bus returning the given frames in order, None meaning a read timeout
+    struct MockBus {
+        frames: std::collections::VecDeque<Option<(u32, Vec<u8>)>>,
+        sent: Vec<(u32, Vec<u8>)>,
+    }
+
+    impl MockBus {
+        fn new(frames: Vec<Option<(u32, Vec<u8>)>>) -> Self {
+            Self {
+                frames: frames.into(),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl FrameIo for MockBus {
+        fn next_frame(&mut self) -> Result<Option<socketcan::CANFrame>, Error> {
+            match self.frames.pop_front() {
+                Some(Some((id, data))) => {
+                    Ok(Some(socketcan::CANFrame::new(id, &data, false, false)?))
                 }
+                _ => Ok(None),
             }
         }
 
-        if response[0] != service + 0x40 {
-            return Err(Error::new("Service identifier of response did not match."));
+        fn send_frame(&mut self, frame: &socketcan::CANFrame) -> Result<(), Error> {
+            self.sent.push((frame.id(), frame.data().to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn single_frame_length_masked() {
+        let mut receiver = IsoTpReceiver::default();
+        let frame = [0x03, 0x41, 0x0d, 0x32, 0xcc, 0xcc, 0xcc, 0xcc];
+
+        assert_eq!(receiver.push(0x7e8, &frame).unwrap(), IsoTpStatus::Complete);
+        assert_eq!(receiver.finish(), vec![0x41, 0x0d, 0x32]);
+    }
+
+    #[test]
+    fn single_frame_rejects_invalid_lengths() {
+        for first in [0x00, 0x08, 0x0f].iter() {
+            let frame = [*first, 0x41, 0x0d, 0x32, 0xcc, 0xcc, 0xcc, 0xcc];
+            assert!(IsoTpReceiver::default().push(0x7e8, &frame).is_err());
         }
 
-        if &response[1..(1 + args.len())] != args {
-            return Err(Error::new("Arguments/PIDs did not match."));
+        // Length exceeding the DLC
+        let frame = [0x05, 0x41, 0x0d, 0x32];
+        assert!(IsoTpReceiver::default().push(0x7e8, &frame).is_err());
+    }
+
+    #[test]
+    fn single_frame_full_length() {
+        let mut receiver = IsoTpReceiver::default();
+        let frame = [0x07, 0x41, 0x00, 0xbe, 0x3f, 0xa8, 0x13, 0xcc];
+
+        assert_eq!(receiver.push(0x7e8, &frame).unwrap(), IsoTpStatus::Complete);
+        assert_eq!(receiver.finish(), frame[1..].to_vec());
+    }
+
+    #[test]
+    fn short_responses_rejected() {
+        // Single frame containing only the service identifier
+        let mut receiver = IsoTpReceiver::default();
+        receiver
+            .push(0x7e8, &[0x01, 0x41, 0x0d, 0xcc, 0xcc, 0xcc, 0xcc, 0xcc])
+            .unwrap();
+        assert!(strip_response(receiver.finish(), 0x01, &[0x0d]).is_err());
+
+        // First frames with a length of 0 and 1
+        for len in 0..=1 {
+            let mut receiver = IsoTpReceiver::default();
+            let frame = [0x10, len, 0x41, 0x0d, 0xcc, 0xcc, 0xcc, 0xcc];
+            assert_eq!(receiver.push(0x7e8, &frame).unwrap(), IsoTpStatus::Complete);
+            assert!(strip_response(receiver.finish(), 0x01, &[0x0d]).is_err());
         }
+    }
+
+    #[test]
+    fn delayed_consecutive_frame() {
+        let mut receiver = IsoTpReceiver::default();
+
+        let first = [0x10, 0x14, 0x49, 0x02, 0x01, 0x57, 0x30, 0x4c];
+        assert_eq!(
+            receiver.push(0x7e8, &first).unwrap(),
+            IsoTpStatus::FlowControl(0x7e0)
+        );
+
+        // The consecutive frames miss the socket's read timeout, which must
+        // not abort the message.
+        assert!(frame_late(&std::io::Error::from(
+            std::io::ErrorKind::WouldBlock
+        )));
+        assert!(frame_late(&std::io::Error::from(
+            std::io::ErrorKind::TimedOut
+        )));
+        assert!(!frame_late(&std::io::Error::from(
+            std::io::ErrorKind::BrokenPipe
+        )));
+
+        let second = [0x21, 0x30, 0x30, 0x30, 0x30, 0x31, 0x32, 0x33];
+        assert_eq!(
+            receiver.push(0x7e8, &second).unwrap(),
+            IsoTpStatus::Incomplete
+        );
+
+        let third = [0x22, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x41];
+        assert_eq!(receiver.push(0x7e8, &third).unwrap(), IsoTpStatus::Complete);
+
+        let response = receiver.finish();
+        assert_eq!(response.len(), 0x14);
+        assert_eq!(&response[..3], &[0x49, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn delayed_consecutive_frame_received() {
+        let mut bus = MockBus::new(vec![
+            Some((0x7e8, vec![0x10, 0x0a, 0x49, 0x02, 0x01, 0x57, 0x30, 0x4c])),
+            None,
+            None,
+            Some((0x7e8, vec![0x21, 0x30, 0x30, 0x30, 0x30, 0xcc, 0xcc, 0xcc])),
+        ]);
+
+        let receiver = IsoTpReceiver::default();
+        let (id, response) = receive_response(&mut bus, receiver, Duration::from_secs(1)).unwrap();
+        assert_eq!(id, 0x7e8);
+        assert_eq!(
+            response,
+            vec![0x49, 0x02, 0x01, 0x57, 0x30, 0x4c, 0x30, 0x30, 0x30, 0x30]
+        );
+        assert_eq!(bus.sent.len(), 1);
+        assert_eq!(bus.sent[0].0, 0x7e0);
+    }
+
+    #[test]
+    fn missing_consecutive_frame_times_out() {
+        let mut bus = MockBus::new(vec![Some((
+            0x7e8,
+            vec![0x10, 0x0a, 0x49, 0x02, 0x01, 0x57, 0x30, 0x4c],
+        ))]);
+
+        let receiver = IsoTpReceiver::default();
+        assert!(receive_response(&mut bus, receiver, Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn interleaved_ecus() {
+        let mut receiver = IsoTpReceiver::default();
+
+        let first = [0x10, 0x0a, 0x49, 0x02, 0x01, 0x57, 0x30, 0x4c];
+        assert_eq!(
+            receiver.push(0x7e8, &first).unwrap(),
+            IsoTpStatus::FlowControl(0x7e0)
+        );
+
+        // Another ECU answering the same functional request
+        let other = [0x03, 0x41, 0x0d, 0x00, 0xcc, 0xcc, 0xcc, 0xcc];
+        assert_eq!(receiver.push(0x7e9, &other).unwrap(), IsoTpStatus::Ignored);
+
+        let other = [0x21, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert_eq!(receiver.push(0x7e9, &other).unwrap(), IsoTpStatus::Ignored);
+
+        let second = [0x21, 0x30, 0x30, 0x30, 0x30, 0xcc, 0xcc, 0xcc];
+        assert_eq!(
+            receiver.push(0x7e8, &second).unwrap(),
+            IsoTpStatus::Complete
+        );
+
+        assert_eq!(
+            receiver.finish(),
+            vec![0x49, 0x02, 0x01, 0x57, 0x30, 0x4c, 0x30, 0x30, 0x30, 0x30]
+        );
+    }
+
+    #[test]
+    fn response_stripped() {
+        let response = vec![0x41, 0x0d, 0x32];
+        assert_eq!(strip_response(response, 0x01, &[0x0d]).unwrap(), vec![0x32]);
 
-        response.truncate(length.unwrap());
+        let response = vec![0x42, 0x0d, 0x32];
+        assert!(strip_response(response, 0x01, &[0x0d]).is_err());
 
-        Ok(response[(1 + args.len())..].to_vec())
+        let response = vec![0x41, 0x0c, 0x32];
+        assert!(strip_response(response, 0x01, &[0x0d]).is_err());
     }
 }
@@ -1,5 +1,7 @@
 //! General protocol-independent traits and types for diagnosis
 
+use serde::Serialize;
+
 use crate::error::Error;
 
 /**
@@ -8,9 +10,9 @@ use crate::error::Error;
  */
 pub trait Diagnose {
     /**
-     * Read Diagnostic Trouble Codes from ECU.
+     * Read Diagnostic Trouble Codes of the given category from ECU.
      */
-    fn read_dtcs(&mut self, pending: bool) -> Result<Vec<DiagnosticTroubleCode>, Error>;
+    fn read_dtcs(&mut self, category: DtcCategory) -> Result<Vec<DiagnosticTroubleCode>, Error>;
 
     /**
      * Instruct ECU to clear Diagnostic Trouble Codes.
@@ -22,6 +24,66 @@ pub trait Diagnose {
      * OBD2 feature and not supported on KWP1281.
      */
     fn read_data(&mut self, pid: u8, freeze_frame: bool) -> Result<DiagnosticData, Error>;
+
+    /**
+     * Return the PIDs supported for reading current data, or None if the
+     * protocol can't tell. Implementations may cache the result.
+     */
+    fn supported_pids(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /**
+     * Discard any cached information about the vehicle, such as the
+     * supported PIDs.
+     */
+    fn invalidate_cache(&mut self) {}
+
+    /**
+     * Instruct the ECU to perform a reset (hard reset / power on). This ends
+     * the diagnostic session, which has to be re-established afterwards on
+     * K line protocols.
+     */
+    fn ecu_reset(&mut self) -> Result<(), Error> {
+        Err(Error::new("ECU reset not supported by protocol."))
+    }
+
+    /**
+     * Keep the diagnostic session alive while idle. This should be called
+     * regularly, protocols only send something if the session would time
+     * out otherwise.
+     */
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/**
+ * Return the formula used for decoding a KWP1281 measuring block value of the
+ * given format, with a and b being its two data bytes.
+ */
+pub fn kwp1281_formula(format: u8) -> Option<&'static str> {
+    Some(match format {
+        0x01 => "0.2 * a * b",
+        0x02 | 0x03 => "0.002 * a * b",
+        0x05 => "0.1 * a * (b - 100)",
+        0x06 | 0x15 => "0.001 * a * b",
+        0x07 | 0x0f => "0.01 * a * b",
+        0x12 => "0.04 * a * b",
+        0x14 => "a * (b - 128) / 128",
+        0x19 => "a / 128 + 1.1421 * b",
+        0x21 => "100 * b / a (100 * b if a = 0)",
+        0x24 => "10 * (256 * a + b)",
+        0x2f => "(b - 128) * a",
+        0x31 => "0.1 * a * b / 4",
+        0x34 => "0.002 * a * b - a",
+        0x36 => "256 * a + b",
+        0x3b => "(256 * a + b) / 32768",
+        0x42 => "a * b / 511.12",
+        _ => {
+            return None;
+        }
+    })
 }
 
 /**
@@ -29,6 +91,7 @@ pub trait Diagnose {
  */
 #[derive(Clone, Debug)]
 pub struct DiagnosticData {
+    service: u8,
     pid: u8,
     data: Vec<u8>,
     kwp1281: bool, // TODO: maybe do this with an enum?
@@ -59,6 +122,7 @@ impl DiagnosticData {
      */
     pub fn from_kwp1281_data(group: u8, data: Vec<u8>) -> Self {
         Self {
+            service: 0x29,
             pid: group,
             data,
             kwp1281: true,
@@ -66,16 +130,26 @@ impl DiagnosticData {
     }
 
     /**
-     * Initialize with data from an OBD2 PID reading.
+     * Initialize with data from an OBD2 PID reading, either of the current
+     * data (service 0x01) or of the freeze frame (service 0x02).
      */
-    pub fn from_obd2_data(pid: u8, data: Vec<u8>) -> Self {
+    pub fn from_obd2_data(service: u8, pid: u8, data: Vec<u8>) -> Self {
         Self {
+            service,
             pid,
             data,
             kwp1281: false,
         }
     }
 
+    /**
+     * Is this a reading of the freeze frame data, i.e. the values recorded
+     * when a DTC was stored?
+     */
+    pub fn freeze_frame(&self) -> bool {
+        !self.kwp1281 && self.service == 0x02
+    }
+
     /**
      * Return the raw bytes.
      */
@@ -83,6 +157,45 @@ impl DiagnosticData {
         &self.data
     }
 
+    /**
+     * Return the bitmap of present oxygen sensors if this is a reading of PID
+     * 0x13 or 0x1d. If bit n is set, the sensor read via PIDs 0x14 + n,
+     * 0x24 + n and 0x34 + n is present.
+     */
+    pub fn oxygen_sensors_present(&self) -> Option<u8> {
+        if self.kwp1281 || self.data.is_empty() {
+            return None;
+        }
+
+        match self.pid {
+            0x13 | 0x1d => Some(self.data[0]),
+            _ => None,
+        }
+    }
+
+    /**
+     * Parse data consisting of a leading byte indicating which values are
+     * supported, followed by the given number of 16-bit values. Values not
+     * marked as supported are returned as None.
+     */
+    fn supported_words(&self, count: usize) -> Result<Vec<Option<u16>>, Error> {
+        if self.data.len() < 1 + 2 * count {
+            return Err(Error::new("Unexpected data length."));
+        }
+
+        Ok(self.data[1..(1 + 2 * count)]
+            .chunks(2)
+            .enumerate()
+            .map(|(i, chunk)| {
+                if self.data[0] & (1 << i) != 0 {
+                    Some(((chunk[0] as u16) << 8) + chunk[1] as u16)
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+
     /*
      * TODO
      *
@@ -151,14 +264,35 @@ impl DiagnosticData {
             return Ok(String::from_utf8_lossy(&self.data[1..]).to_string());
         }
 
+        let fields = self.kwp1281_fields()?;
+        if fields.is_empty() {
+            return Ok("No data".to_string());
+        }
+
+        Ok(fields
+            .iter()
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<&str>>()
+            .join(" "))
+    }
+
+    /**
+     * Return the formatted values of a KWP1281 group reading, together with
+     * their field index (starting at 1). Empty fields are skipped.
+     */
+    pub fn kwp1281_fields(&self) -> Result<Vec<(usize, String)>, Error> {
+        if !self.kwp1281 || self.data.is_empty() || self.data[0] == 0x3f {
+            return Err(Error::new("Not a KWP1281 measuring group."));
+        }
+
         if self.data.len() % 3 != 0 {
             return Err(Error::new("Unexpected data length."));
         }
 
-        // Otherwise, data contains groups (usually 4) of 1 format identifier,
-        // and 2 data bytes. Some may be empty (0x25, 0x00, 0x00).
-        let mut output = String::from("");
-        for chunk in self.data.chunks(3) {
+        // Data contains groups (usually 4) of 1 format identifier, and 2
+        // data bytes. Some may be empty (0x25, 0x00, 0x00).
+        let mut fields = Vec::with_capacity(4);
+        for (i, chunk) in self.data.chunks(3).enumerate() {
             if chunk == [0x25, 0x00, 0x00] {
                 continue;
             }
@@ -167,7 +301,7 @@ impl DiagnosticData {
             let a = chunk[1];
             let b = chunk[2];
 
-            output.push_str(&match format {
+            let value = match format {
                 0x01 => format!("{:6.1} rpm ", a as f32 * b as f32 * 0.2),
                 0x02 => format!("{:7.3} % ", a as f32 * b as f32 * 0.002),
                 0x03 => format!("{:7.3} deg ", a as f32 * b as f32 * 0.002),
@@ -194,14 +328,12 @@ impl DiagnosticData {
                 0x3b => format!("{:5.3} ", (((a as u16) << 8) + b as u16) as f32 / 32768.0),
                 0x42 => format!("{:6.3} V ", a as f32 * b as f32 / 511.12),
                 _ => format!("{:02x?} ", chunk),
-            })
-        }
+            };
 
-        if output == "" {
-            output = "No data".to_string()
+            fields.push((i + 1, value.trim_end().to_string()));
         }
 
-        Ok(output.trim_end().to_string())
+        Ok(fields)
     }
 
     fn floats_obd2(&self) -> Result<Vec<f32>, Error> {
@@ -209,6 +341,13 @@ impl DiagnosticData {
         let data = &self.data;
 
         Ok(match pid {
+            0x01 => {
+                let status = MonitorStatus::from_obd2_data(data)?;
+                vec![
+                    status.mil.unwrap_or(false) as u8 as f32,
+                    status.dtc_count.unwrap_or(0) as f32,
+                ]
+            }
             0x04 => vec![data[0] as f32 / 2.55],
             0x05 => vec![(data[0] as i16 - 40) as f32],
             0x06 | 0x07 | 0x08 | 0x09 => vec![data[0] as f32 / 1.25 - 100.0],
@@ -227,6 +366,7 @@ impl DiagnosticData {
                     vec![data[0] as f32 / 200.0, data[1] as f32 / 1.28 - 100.0]
                 }
             }
+            0x1e => vec![data.get(0).map_or(f32::NAN, |d| (d & 0x01) as f32)],
             0x1f => vec![((data[0] as u16) << 8 + data[1] as u16) as f32],
             0x21 => vec![((data[0] as u16) << 8 + data[1] as u16) as f32],
             0x22 => vec![(data[0] as f32 * 256.0 + data[1] as f32) * 0.079],
@@ -297,6 +437,60 @@ impl DiagnosticData {
                 (data[3] as i16 - 125) as f32,
                 (data[4] as i16 - 125) as f32,
             ],
+            0x78 | 0x79 => self
+                .supported_words(4)?
+                .iter()
+                .map(|t| t.map_or(f32::NAN, |t| t as f32 / 10.0 - 40.0))
+                .collect(),
+            0x7a | 0x7b => self
+                .supported_words(3)?
+                .iter()
+                .map(|p| p.map_or(f32::NAN, |p| p as f32 / 100.0))
+                .collect(),
+            0x7c => self
+                .supported_words(4)?
+                .iter()
+                .map(|t| t.map_or(f32::NAN, |t| t as f32 / 10.0 - 40.0))
+                .collect(),
+            0x83 => self
+                .supported_words(2)?
+                .iter()
+                .map(|c| c.map_or(f32::NAN, |c| c as f32))
+                .collect(),
+            0xa1 => self
+                .supported_words(4)?
+                .iter()
+                .map(|c| c.map_or(f32::NAN, |c| c as f32))
+                .collect(),
+            0x9a => {
+                if data.len() < 6 {
+                    return Err(Error::new("Unexpected data length."));
+                }
+
+                let voltage = if data[0] & 0x02 != 0 {
+                    (data[2] as f32 * 256.0 + data[3] as f32) / 64.0
+                } else {
+                    f32::NAN
+                };
+                let current = if data[0] & 0x04 != 0 {
+                    (((data[4] as u16) << 8) + data[5] as u16) as i16 as f32 / 10.0
+                } else {
+                    f32::NAN
+                };
+
+                vec![voltage, current]
+            }
+            0x9b => {
+                if data.len() < 4 {
+                    return Err(Error::new("Unexpected data length."));
+                }
+
+                vec![
+                    data[1] as f32 / 4.0,
+                    (data[2] as i16 - 40) as f32,
+                    data[3] as f32 / 2.55,
+                ]
+            }
             0xa6 => vec![
                 (((data[0] as u64) << 24)
                     + ((data[1] as u64) << 16)
@@ -314,9 +508,35 @@ impl DiagnosticData {
         let data = &self.data;
 
         Ok(match pid {
+            0x01 => {
+                format!("{}", MonitorStatus::from_obd2_data(data)?)
+            },
             0x02 => {
                 let code = ((data[0] as u16) << 8) + data[1] as u16;
-                format!("Freeze DTC: {}", DiagnosticTroubleCode::Obd(code))
+                format!("Freeze DTC: {}", DiagnosticTroubleCode::Obd(code, None))
+            },
+            0x03 => {
+                let status = |value: u8| match value {
+                    0x00 => "Engine off",
+                    0x01 => "Open loop due to insufficient engine temperature",
+                    0x02 => "Closed loop, using oxygen sensor feedback",
+                    0x04 => "Open loop due to engine load or fuel cut due to deceleration",
+                    0x08 => "Open loop due to system failure",
+                    0x10 => "Closed loop, but fault in feedback system",
+                    _ => "Unknown"
+                };
+
+                match (data.get(0), data.get(1)) {
+                    (Some(bank1), Some(bank2)) => {
+                        format!("Fuel system status: bank 1: {}, bank 2: {}",
+                            status(*bank1),
+                            status(*bank2))
+                    }
+                    // Vehicles with only a single fuel system may return
+                    // just one byte.
+                    (Some(system), None) => format!("Fuel system status: {}", status(*system)),
+                    _ => format!("Fuel system status: {:02x?}", data),
+                }
             },
             0x04 => {
                 format!("Calculated engine load: {:6.2} %", data[0] as f32 / 2.55)
@@ -354,6 +574,20 @@ impl DiagnosticData {
             0x11 => {
                 format!("Throttle position: {:3} %", data[0] as f32 / 2.55)
             },
+            0x12 => {
+                let status = match data.get(0) {
+                    Some(0x01) => "Upstream",
+                    Some(0x02) => "Downstream of catalytic converter",
+                    Some(0x04) => "From the outside atmosphere or off",
+                    Some(0x08) => "Pump commanded on for diagnostics",
+                    Some(_) => "Unknown",
+                    None => {
+                        return Ok(format!("Commanded secondary air status: {:02x?}", data));
+                    }
+                };
+
+                format!("Commanded secondary air status: {}", status)
+            },
             0x14 | 0x15 | 0x16 | 0x17 | 0x18 | 0x19 | 0x1a | 0x1b => {
                 let id = pid - 0x13;
                 if data[1] == 0xff {
@@ -367,6 +601,31 @@ impl DiagnosticData {
                         data[1] as f32 / 1.28 - 100.0)
                 }
             },
+            0x13 | 0x1d => {
+                let present = match data.get(0) {
+                    Some(p) => *p,
+                    None => {
+                        return Ok(format!("Oxygen sensors present: {:02x?}", data));
+                    }
+                };
+
+                // PID 0x13 describes 2 banks with 4 sensors each, PID 0x1d 4
+                // banks with 2 sensors each.
+                let sensors_per_bank = if pid == 0x13 { 4 } else { 2 };
+                let banks: Vec<String> = (0..(8 / sensors_per_bank))
+                    .map(|bank| {
+                        let sensors: Vec<String> = (0..sensors_per_bank)
+                            .filter(|s| present & (1 << (bank * sensors_per_bank + s)) != 0)
+                            .map(|s| (s + 1).to_string())
+                            .collect();
+
+                        format!("bank {}: {}", bank + 1,
+                            if sensors.is_empty() { String::from("none") } else { sensors.join(", ") })
+                    })
+                    .collect();
+
+                format!("Oxygen sensors present: {}", banks.join("; "))
+            },
             0x1c => {
                 format!("OBD standard: {}", match data[0] {
                     1 => "OBD-II as defined by the CARB",
@@ -405,6 +664,11 @@ impl DiagnosticData {
                     _ => "Unknown"
                 })
             },
+            0x1e => match data.get(0) {
+                Some(status) => format!("Auxiliary input status: PTO {}",
+                    if status & 0x01 != 0 { "active" } else { "inactive" }),
+                None => format!("Auxiliary input status: {:02x?}", data),
+            },
             0x1f => {
                 format!("Run time since engine start: {:5} s", (data[0] as u16) << 8 + data[1] as u16)
             },
@@ -459,6 +723,9 @@ impl DiagnosticData {
                     if pid <= 0x3d { 1 } else { 2 },
                     (data[0] as f32 * 256.0 + data[1] as f32) / 10.0 - 40.0)
             },
+            0x41 => {
+                format!("{}", MonitorStatus::from_drive_cycle_data(data)?)
+            },
             0x42 => {
                 format!("Control module voltage: {:6.3} V", (data[0] as f32 * 256.0 + data[1] as f32) / 1000.0)
             },
@@ -572,7 +839,9 @@ impl DiagnosticData {
                 format!("Relative accelerator pedal position: {:6.2} %", data[0] as f32 / 2.55)
             },
             0x5b => {
-                format!("Hybrid battery pack remaining life: {:6.2} %", data[0] as f32 / 2.55)
+                // The remaining life is the state of charge, as far as the
+                // hybrid system is concerned.
+                format!("Hybrid battery pack remaining life (state of charge): {:6.2} %", data[0] as f32 / 2.55)
             },
             0x5c => {
                 format!("Engine oil temperature: {:3} C", data[0] as i16 - 40)
@@ -602,6 +871,111 @@ impl DiagnosticData {
                     data[3] as i16 - 125,
                     data[4] as i16 - 125)
             },
+            0x78 | 0x79 => {
+                let temperatures: Vec<String> = self.supported_words(4)?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, t)| match t {
+                        Some(t) => format!("Sensor {}: {:6.1} C", i + 1, *t as f32 / 10.0 - 40.0),
+                        None => format!("Sensor {}: N/A", i + 1),
+                    })
+                    .collect();
+
+                format!("Exhaust gas temperature: Bank {}: {}",
+                    if pid == 0x78 { 1 } else { 2 },
+                    temperatures.join(", "))
+            },
+            0x7a | 0x7b => {
+                let names = ["delta", "inlet", "outlet"];
+                let pressures: Vec<String> = self.supported_words(3)?
+                    .iter()
+                    .zip(names.iter())
+                    .map(|(p, name)| match p {
+                        Some(p) => format!("{}: {:7.2} kPa", name, *p as f32 / 100.0),
+                        None => format!("{}: N/A", name),
+                    })
+                    .collect();
+
+                format!("Diesel particulate filter pressure: Bank {}: {}",
+                    if pid == 0x7a { 1 } else { 2 },
+                    pressures.join(", "))
+            },
+            0x7c => {
+                let names = ["Bank 1 inlet", "Bank 1 outlet", "Bank 2 inlet", "Bank 2 outlet"];
+                let temperatures: Vec<String> = self.supported_words(4)?
+                    .iter()
+                    .zip(names.iter())
+                    .map(|(t, name)| match t {
+                        Some(t) => format!("{}: {:6.1} C", name, *t as f32 / 10.0 - 40.0),
+                        None => format!("{}: N/A", name),
+                    })
+                    .collect();
+
+                format!("Diesel particulate filter temperature: {}", temperatures.join(", "))
+            },
+            0x8b => {
+                // The layout of the soot/ash load and regeneration status
+                // differs between manufacturers, so just dump it.
+                format!("Diesel aftertreatment status: {:02x?}", data)
+            },
+            0x83 | 0xa1 => {
+                let count = if pid == 0x83 { 2 } else { 4 };
+                let concentrations: Vec<String> = self.supported_words(count)?
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| match c {
+                        Some(c) => format!("Sensor {}: {:5} ppm", i + 1, c),
+                        None => format!("Sensor {}: N/A", i + 1),
+                    })
+                    .collect();
+
+                format!("{}NOx sensor concentration: {}",
+                    if pid == 0xa1 { "Corrected " } else { "" },
+                    concentrations.join(", "))
+            },
+            0x9a => {
+                // Records are variable in length, the first byte indicates
+                // which of the following values are actually supported.
+                if data.len() < 6 {
+                    return Err(Error::new("Unexpected data length."));
+                }
+
+                let mut values = Vec::with_capacity(3);
+                if data[0] & 0x01 != 0 {
+                    values.push(format!("charging state: {}", match data[1] & 0x03 {
+                        0 => "Charge sustaining",
+                        1 => "Charge depleting",
+                        2 => "Charge increasing",
+                        _ => "Unknown"
+                    }));
+                }
+                if data[0] & 0x02 != 0 {
+                    values.push(format!("voltage: {:8.3} V",
+                        (data[2] as f32 * 256.0 + data[3] as f32) / 64.0));
+                }
+                if data[0] & 0x04 != 0 {
+                    values.push(format!("current: {:7.1} A",
+                        (((data[4] as u16) << 8) + data[5] as u16) as i16 as f32 / 10.0));
+                }
+
+                if values.is_empty() {
+                    values.push(String::from("N/A"));
+                }
+
+                format!("Hybrid/EV battery pack: {}", values.join(", "))
+            },
+            0x9b => {
+                // Despite following 0x9a, this is diesel exhaust fluid (AdBlue)
+                // data, not hybrid/EV data.
+                if data.len() < 4 {
+                    return Err(Error::new("Unexpected data length."));
+                }
+
+                format!("Diesel exhaust fluid: concentration: {:6.2} %, tank temperature: {:3} C, tank level: {:6.2} %",
+                    data[1] as f32 / 4.0,
+                    data[2] as i16 - 40,
+                    data[3] as f32 / 2.55)
+            },
             0xa6 => {
                 format!("Odometer: {:6} km",
                     ((data[0] as u64) << 24) + ((data[1] as u64) << 16) +
@@ -628,7 +1002,9 @@ impl DiagnosticData {
     /**
      * Return data as floating point values if possible.
      *
-     * For OBD2, this will mostly be single-value Vecs.
+     * For OBD2, this will mostly be single-value Vecs. PIDs with optional
+     * values, such as the exhaust gas temperatures, always return the same
+     * number of values, using NaN for the ones the ECU doesn't support.
      */
     pub fn floats(&self) -> Result<Vec<f32>, Error> {
         if self.kwp1281 {
@@ -639,12 +1015,301 @@ impl DiagnosticData {
     }
 }
 
+/**
+ * Status of a single OBD2 readiness monitor.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct ReadinessMonitor {
+    /// Name of the monitored system
+    pub name: &'static str,
+    /// Is the monitor supported by the vehicle?
+    pub supported: bool,
+    /// Has the monitor's test been completed?
+    pub complete: bool,
+}
+
+/**
+ * Type for the readiness monitor status, either since DTCs were cleared, as
+ * returned by PID 0x01, or for the current drive cycle, as returned by PID
+ * 0x41. The former also includes the MIL status and the number of stored DTCs,
+ * as needed for emissions inspections.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct MonitorStatus {
+    /// Is the MIL (check engine light) on? Not included for PID 0x41.
+    pub mil: Option<bool>,
+    /// Number of emissions-related DTCs. Not included for PID 0x41.
+    pub dtc_count: Option<u8>,
+    /// Compression ignition (diesel) engine, otherwise spark ignition
+    pub compression_ignition: bool,
+    /// Status of the readiness monitors, both continuous and non-continuous
+    pub monitors: Vec<ReadinessMonitor>,
+}
+
+impl MonitorStatus {
+    /**
+     * Parse the 4 data bytes returned for PID 0x01.
+     */
+    pub fn from_obd2_data(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 4 {
+            return Err(Error::new("Unexpected data length."));
+        }
+
+        let (compression_ignition, monitors) = Self::readiness_monitors(&data[1..4]);
+
+        Ok(Self {
+            mil: Some(data[0] & 0x80 != 0),
+            dtc_count: Some(data[0] & 0x7f),
+            compression_ignition,
+            monitors,
+        })
+    }
+
+    /**
+     * Parse the 4 data bytes returned for PID 0x41. The first byte is
+     * reserved, the rest uses the same layout as PID 0x01, with monitors being
+     * enabled and complete for the current drive cycle.
+     */
+    pub fn from_drive_cycle_data(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 4 {
+            return Err(Error::new("Unexpected data length."));
+        }
+
+        let (compression_ignition, monitors) = Self::readiness_monitors(&data[1..4]);
+
+        Ok(Self {
+            mil: None,
+            dtc_count: None,
+            compression_ignition,
+            monitors,
+        })
+    }
+
+    /**
+     * Return the supported monitors that have not completed their tests yet.
+     */
+    pub fn incomplete_monitors(&self) -> Vec<&ReadinessMonitor> {
+        self.monitors
+            .iter()
+            .filter(|m| m.supported && !m.complete)
+            .collect()
+    }
+
+    /**
+     * Is the vehicle ready for an emissions inspection? This requires the MIL
+     * to be off, and at most the given number of incomplete monitors. Always
+     * false for the drive cycle status, since it lacks the MIL status.
+     */
+    pub fn ready_for_inspection(&self, allowed_incomplete: usize) -> bool {
+        self.mil == Some(false) && self.incomplete_monitors().len() <= allowed_incomplete
+    }
+
+    /**
+     * Parse the 3 readiness monitor bytes shared by PIDs 0x01 and 0x41,
+     * returning whether the engine is compression ignition and the monitors.
+     */
+    fn readiness_monitors(data: &[u8]) -> (bool, Vec<ReadinessMonitor>) {
+        let compression_ignition = data[0] & 0x08 != 0;
+
+        // Continuous monitors, support and completion are in the same byte.
+        let mut monitors: Vec<ReadinessMonitor> = ["Misfire", "Fuel system", "Components"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| ReadinessMonitor {
+                name,
+                supported: data[0] & (1 << i) != 0,
+                complete: data[0] & (1 << (i + 4)) == 0,
+            })
+            .collect();
+
+        // Non-continuous monitors differ between spark and compression
+        // ignition engines.
+        let names = if compression_ignition {
+            [
+                "NMHC catalyst",
+                "NOx/SCR monitor",
+                "",
+                "Boost pressure",
+                "",
+                "Exhaust gas sensor",
+                "PM filter",
+                "EGR and/or VVT system",
+            ]
+        } else {
+            [
+                "Catalyst",
+                "Heated catalyst",
+                "Evaporative system",
+                "Secondary air system",
+                "A/C refrigerant",
+                "Oxygen sensor",
+                "Oxygen sensor heater",
+                "EGR system",
+            ]
+        };
+
+        for (i, name) in names.iter().enumerate() {
+            // reserved bits
+            if name.is_empty() {
+                continue;
+            }
+
+            monitors.push(ReadinessMonitor {
+                name,
+                supported: data[1] & (1 << i) != 0,
+                complete: data[2] & (1 << i) == 0,
+            });
+        }
+
+        (compression_ignition, monitors)
+    }
+}
+
+impl std::fmt::Display for MonitorStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.mil, self.dtc_count) {
+            (Some(mil), Some(dtc_count)) => write!(
+                f,
+                "Monitor status since DTCs cleared: MIL: {}, DTCs: {}, ",
+                if mil { "on" } else { "off" },
+                dtc_count
+            )?,
+            _ => write!(f, "Monitor status this drive cycle: ")?,
+        }
+
+        write!(
+            f,
+            "{} ignition",
+            if self.compression_ignition { "compression" } else { "spark" }
+        )?;
+
+        for monitor in self.monitors.iter().filter(|m| m.supported) {
+            write!(
+                f,
+                "\n    {:22} {}",
+                format!("{}:", monitor.name),
+                if monitor.complete { "complete" } else { "incomplete" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Decoded status of a DTC.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DtcStatus {
+    /// Is the malfunction present at the time of the request?
+    pub test_failed: bool,
+    /// Is the DTC still maturing, i.e. detected, but not yet confirmed?
+    pub pending: bool,
+    /// Has the DTC been confirmed and stored?
+    pub confirmed: bool,
+    /// Does the DTC request the MIL/warning lamp to be on?
+    pub mil_requested: bool,
+    /// Has the test not been completed since DTCs were last cleared?
+    pub test_incomplete: bool,
+}
+
+impl DtcStatus {
+    /**
+     * Status of an OBD2 DTC, which is only implied by the category it was
+     * read as. Permanent DTCs are confirmed DTCs that can't be cleared.
+     */
+    pub fn from_obd2_category(category: DtcCategory) -> Self {
+        Self {
+            pending: category == DtcCategory::Pending,
+            confirmed: category != DtcCategory::Pending,
+            ..Self::default()
+        }
+    }
+
+    /**
+     * Decode a KWP2000 (ISO 14230-3) DTC status byte. Bit 7 indicates the
+     * warning lamp status, bits 6-5 the storage state, and bit 4 the
+     * readiness flag. The lower bits contain a manufacturer-specific symptom.
+     */
+    pub fn from_kwp2000_status(status: u8) -> Self {
+        let storage_state = (status >> 5) & 0x03;
+
+        Self {
+            test_failed: storage_state == 0x03,
+            pending: storage_state == 0x02,
+            confirmed: storage_state == 0x01 || storage_state == 0x03,
+            mil_requested: status & 0x80 != 0,
+            test_incomplete: status & 0x10 != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for DtcStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let flags: Vec<&str> = [
+            (self.test_failed, "test failed"),
+            (self.pending, "pending"),
+            (self.confirmed, "confirmed"),
+            (self.mil_requested, "MIL requested"),
+            (self.test_incomplete, "test incomplete"),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| *name)
+        .collect();
+
+        if flags.is_empty() {
+            write!(f, "no status flags set")
+        } else {
+            write!(f, "{}", flags.join(", "))
+        }
+    }
+}
+
+/**
+ * Category of DTCs, i.e. which set of DTCs they were read from.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum DtcCategory {
+    /// Confirmed DTCs, as read by default
+    Stored,
+    /// DTCs detected during the current or last driving cycle, which are not
+    /// confirmed yet
+    Pending,
+    /// DTCs that can't be cleared by the tester, only by the ECU itself once
+    /// the fault is no longer detected
+    Permanent,
+}
+
+impl DtcCategory {
+    /**
+     * Return the OBD2 service used to read DTCs of this category.
+     */
+    pub fn obd2_service(&self) -> u8 {
+        match self {
+            Self::Stored => 0x03,
+            Self::Pending => 0x07,
+            Self::Permanent => 0x0a,
+        }
+    }
+}
+
+impl std::fmt::Display for DtcCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Stored => write!(f, "stored"),
+            Self::Pending => write!(f, "pending"),
+            Self::Permanent => write!(f, "permanent"),
+        }
+    }
+}
+
 /**
  * Type for a DTC (Diagnostic Trouble Code).
  *
  * This can be either a proper OBD2 DTC, which are standardized, or an OEM
- * fault code as returned by the KWP1281 protocol used by VAG, even though
- * those are technically not DTCs.
+ * fault code as returned by the KWP1281 and KWP2000 protocols used by VAG,
+ * even though the former are technically not DTCs.
  *
  * The [std::fmt::Display] trait displays the OBD2 codes in their well known
  * form with a leading category letter, such as P0171.
@@ -653,15 +1318,19 @@ impl DiagnosticData {
 pub enum DiagnosticTroubleCode {
     /// OEM (VAG) code, with a code and a third byte providing auxiliary info
     Oem(u16, u8),
-    /// Standardized OBD2 code
-    Obd(u16),
+    /// OEM code read via KWP2000, with a code and the DTC status byte
+    Kwp2000(u16, u8),
+    /// Standardized OBD2 code, with the category it was read as if known
+    Obd(u16, Option<DtcCategory>),
 }
 
 impl std::fmt::Display for DiagnosticTroubleCode {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Self::Oem(code, _) => write!(f, "OEM (VAG) Code {:05}", code),
-            Self::Obd(code) => {
+            Self::Oem(code, _) | Self::Kwp2000(code, _) => {
+                write!(f, "OEM (VAG) Code {:05}", code)
+            }
+            Self::Obd(code, _) => {
                 let category = match code >> 14 {
                     0 => "P",
                     1 => "C",
@@ -684,7 +1353,8 @@ impl DiagnosticTroubleCode {
     pub fn more_info(&self) -> String {
         match self {
             Self::Oem(code, status) => format!("0x{:04x}, third byte: 0x{:02x}", code, status),
-            Self::Obd(_) => self.name().unwrap_or(if self.manufacturer_specific() {
+            Self::Kwp2000(code, status) => format!("0x{:04x}, status: 0x{:02x}", code, status),
+            Self::Obd(_, _) => self.name().unwrap_or(if self.manufacturer_specific() {
                 String::from("Manufacturer Specific")
             } else {
                 String::from("Unknown Code")
@@ -692,14 +1362,44 @@ impl DiagnosticTroubleCode {
         }
     }
 
+    /**
+     * Return the decoded status of the DTC, if the protocol provides one.
+     * KWP1281 fault codes only come with a manufacturer-specific status.
+     */
+    pub fn status(&self) -> Option<DtcStatus> {
+        match self {
+            Self::Oem(_, _) => None,
+            Self::Kwp2000(_, status) => Some(DtcStatus::from_kwp2000_status(*status)),
+            Self::Obd(_, category) => category.map(DtcStatus::from_obd2_category),
+        }
+    }
+
+    /**
+     * Return the category of the DTC. For KWP2000, this is derived from the
+     * storage state in the status byte. KWP1281 only knows stored codes.
+     */
+    pub fn category(&self) -> Option<DtcCategory> {
+        match self {
+            Self::Oem(_, _) => Some(DtcCategory::Stored),
+            Self::Kwp2000(_, _) => self.status().map(|status| {
+                if status.pending {
+                    DtcCategory::Pending
+                } else {
+                    DtcCategory::Stored
+                }
+            }),
+            Self::Obd(_, category) => *category,
+        }
+    }
+
     /**
      * Is the code specific to the manufacturer? This is always true for OEM
      * codes, and true for OBD2 DTCs if the first numerical digit is 1.
      */
     pub fn manufacturer_specific(&self) -> bool {
         match self {
-            Self::Oem(_, _) => true,
-            Self::Obd(code) => ((code >> 12) & 0x03) == 1,
+            Self::Oem(_, _) | Self::Kwp2000(_, _) => true,
+            Self::Obd(code, _) => ((code >> 12) & 0x03) == 1,
         }
     }
 
@@ -709,17 +1409,26 @@ impl DiagnosticTroubleCode {
      * standardized as far as I can tell.
      */
     pub fn help(&self) -> String {
-        let mut help = String::from("    further resources:\n");
+        let mut help = String::new();
+
+        if let Some(structure) = self.structure() {
+            help += "    code structure:\n";
+            for line in structure {
+                help += &format!("      - {}\n", line);
+            }
+        }
+
+        help += "    further resources:\n";
 
         help += &format!(
             "      - http://wiki.ross-tech.com/wiki/index.php?search={}\n",
             match self {
-                Self::Oem(code, _) => format!("{:05}", code),
-                Self::Obd(_) => format!("{}", self),
+                Self::Oem(code, _) | Self::Kwp2000(code, _) => format!("{:05}", code),
+                Self::Obd(_, _) => format!("{}", self),
             }
         );
 
-        if let Self::Obd(_) = self {
+        if let Self::Obd(_, _) = self {
             let formatted = format!("{}", self);
 
             if !self.manufacturer_specific() && formatted.chars().nth(0).unwrap() != 'C' {
@@ -741,6 +1450,68 @@ impl DiagnosticTroubleCode {
         help
     }
 
+    /**
+     * Break an OBD2 DTC down into its system, whether it is defined by SAE or
+     * the manufacturer, and the subsystem, as defined by SAE J2012 (which
+     * J1979 refers to). This gives some orientation for unknown codes.
+     */
+    fn structure(&self) -> Option<Vec<String>> {
+        let code = match self {
+            Self::Obd(code, _) => *code,
+            _ => {
+                return None;
+            }
+        };
+
+        let system = (code >> 14) & 0x03;
+        let digit = (code >> 12) & 0x03;
+        let subsystem = (code >> 8) & 0x0f;
+
+        let mut lines = vec![format!(
+            "system: {}",
+            match system {
+                0 => "powertrain",
+                1 => "chassis",
+                2 => "body",
+                _ => "network",
+            }
+        )];
+
+        lines.push(String::from(match (system, digit) {
+            (_, 0) => "generic code, defined by SAE",
+            (_, 1) => "manufacturer specific code",
+            (0, 2) => "generic code, defined by SAE",
+            (0, 3) if subsystem < 4 => "manufacturer specific code",
+            (0, 3) => "generic code, reserved by SAE",
+            (_, 2) => "manufacturer specific code",
+            _ => "reserved by SAE",
+        }));
+
+        // Only the generic powertrain and network codes have standardized
+        // subsystem groups.
+        let group = match (system, digit, subsystem) {
+            (0, 0..=2, 0x0) => Some("fuel and air metering, auxiliary emission controls"),
+            (0, 0..=2, 0x1) | (0, 0..=2, 0x2) => Some("fuel and air metering"),
+            (0, 0..=2, 0x3) => Some("ignition system or misfire"),
+            (0, 0..=2, 0x4) => Some("auxiliary emission controls"),
+            (0, 0..=2, 0x5) => Some("vehicle speed, idle control and auxiliary inputs"),
+            (0, 0..=2, 0x6) => Some("computer and auxiliary outputs"),
+            (0, 0..=2, 0x7..=0x9) => Some("transmission"),
+            (0, 0..=2, 0xa) => Some("hybrid propulsion"),
+            (3, 0, 0x0) => Some("network electrical"),
+            (3, 0, 0x1) | (3, 0, 0x2) => Some("lost communication"),
+            (3, 0, 0x3) => Some("software incompatibility"),
+            (3, 0, 0x4) | (3, 0, 0x5) => Some("invalid data received"),
+            _ => None,
+        };
+
+        if let Some(group) = group {
+            lines.push(format!("subsystem {:X}: {}", subsystem, group));
+        }
+
+        Some(lines)
+    }
+
     /**
      * Return the meaning of an OBD2 DTC if known.
      *
@@ -2819,3 +3590,92 @@ impl DiagnosticTroubleCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn help_powertrain() {
+        let help = DiagnosticTroubleCode::Obd(0x0301, None).help();
+        assert!(help.contains("system: powertrain"));
+        assert!(help.contains("generic code, defined by SAE"));
+        assert!(help.contains("subsystem 3: ignition system or misfire"));
+        assert!(help.contains("https://www.obd-codes.com/p0301"));
+
+        let help = DiagnosticTroubleCode::Obd(0x1234, None).help();
+        assert!(help.contains("manufacturer specific code"));
+        assert!(help.contains("https://www.obd-codes.com/trouble_codes/"));
+    }
+
+    #[test]
+    fn help_chassis() {
+        let help = DiagnosticTroubleCode::Obd(0x4035, None).help();
+        assert!(help.contains("system: chassis"));
+        assert!(help.contains("generic code, defined by SAE"));
+        assert!(!help.contains("subsystem"));
+        assert!(help.contains("obd-ii-c-chassis-codes.php"));
+    }
+
+    #[test]
+    fn help_body() {
+        let help = DiagnosticTroubleCode::Obd(0x9234, None).help();
+        assert!(help.contains("system: body"));
+        assert!(help.contains("manufacturer specific code"));
+        assert!(help.contains("https://www.obd-codes.com/body-codes"));
+    }
+
+    #[test]
+    fn help_network() {
+        let help = DiagnosticTroubleCode::Obd(0xc100, None).help();
+        assert!(help.contains("system: network"));
+        assert!(help.contains("subsystem 1: lost communication"));
+        assert!(help.contains("https://www.obd-codes.com/u0100"));
+
+        let help = DiagnosticTroubleCode::Obd(0xd100, None).help();
+        assert!(help.contains("manufacturer specific code"));
+        assert!(help.contains("https://www.obd-codes.com/u1000"));
+    }
+
+    #[test]
+    fn empty_responses() {
+        for pid in &[0x03, 0x12, 0x13, 0x1d, 0x1e] {
+            let data = DiagnosticData::from_obd2_data(0x01, *pid, Vec::new());
+            assert!(data.formatted().is_ok());
+        }
+
+        let data = DiagnosticData::from_obd2_data(0x01, 0x1e, Vec::new());
+        assert!(data.floats().unwrap()[0].is_nan());
+    }
+
+    #[test]
+    fn optional_values_are_nan() {
+        // Only sensors 1 and 3 supported
+        let data = DiagnosticData::from_obd2_data(
+            0x01,
+            0x78,
+            vec![0x05, 0x0f, 0xa0, 0x00, 0x00, 0x0f, 0xa0, 0x00, 0x00],
+        );
+        let floats = data.floats().unwrap();
+        assert_eq!(floats.len(), 4);
+        assert_eq!(floats[0], 360.0);
+        assert!(floats[1].is_nan());
+        assert_eq!(floats[2], 360.0);
+        assert!(floats[3].is_nan());
+
+        // Only the voltage supported
+        let data =
+            DiagnosticData::from_obd2_data(0x01, 0x9a, vec![0x02, 0x00, 0x64, 0x00, 0x00, 0x00]);
+        let floats = data.floats().unwrap();
+        assert_eq!(floats.len(), 2);
+        assert_eq!(floats[0], 400.0);
+        assert!(floats[1].is_nan());
+    }
+
+    #[test]
+    fn help_oem() {
+        let help = DiagnosticTroubleCode::Oem(17978, 0x23).help();
+        assert!(!help.contains("code structure"));
+        assert!(help.contains("search=17978"));
+    }
+}
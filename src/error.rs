@@ -4,6 +4,7 @@
 #[derive(Debug, Default)]
 pub struct Error {
     msg: String,
+    connection_lost: bool,
 }
 
 impl Error {
@@ -11,7 +12,30 @@ impl Error {
      * Creates new error with the given message.
      */
     pub fn new<T: Into<String>>(m: T) -> Self {
-        Self { msg: m.into() }
+        Self {
+            msg: m.into(),
+            connection_lost: false,
+        }
+    }
+
+    /**
+     * Creates new error with the given message, indicating that the
+     * connection to the vehicle was lost, e.g. because the ECU stopped
+     * responding or the bus went down.
+     */
+    pub fn connection_lost<T: Into<String>>(m: T) -> Self {
+        Self {
+            msg: m.into(),
+            connection_lost: true,
+        }
+    }
+
+    /**
+     * Returns whether the connection was lost, in which case reinitializing
+     * the protocol might help, unlike e.g. for negative responses.
+     */
+    pub fn is_connection_lost(&self) -> bool {
+        self.connection_lost
     }
 }
 
@@ -25,10 +49,14 @@ impl std::error::Error for Error {}
 
 macro_rules! error_from {
     ( $t:ty ) => {
+        error_from!($t, false);
+    };
+    ( $t:ty, $lost:expr ) => {
         impl From<$t> for Error {
             fn from(error: $t) -> Self {
                 Error {
                     msg: format!("{}", error),
+                    connection_lost: $lost,
                 }
             }
         }
@@ -38,6 +66,7 @@ macro_rules! error_from {
 error_from!(std::io::Error);
 error_from!(std::num::ParseIntError);
 error_from!(gpio_cdev::errors::Error);
-error_from!(serial::Error);
-error_from!(socketcan::CANSocketOpenError);
+error_from!(serial::Error, true);
+error_from!(socketcan::CANSocketOpenError, true);
 error_from!(socketcan::ConstructionError);
+error_from!(toml::de::Error);
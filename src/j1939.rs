@@ -0,0 +1,496 @@
+//! Diagnostics for heavy-duty vehicles via SAE J1939 on the CAN bus
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use log::{debug, trace, warn};
+use socketcan;
+
+use crate::can::*;
+use crate::error::*;
+use crate::misc::*;
+
+/// Default bit rate of J1939 networks
+pub const DEFAULT_BIT_RATE: u64 = 250000;
+
+/// Source address used by the tester (off-board diagnostic-service tool)
+const TESTER_ADDRESS: u8 = 0xf9;
+
+/// Global destination address
+const GLOBAL_ADDRESS: u8 = 0xff;
+
+/// PGN of the request message
+const PGN_REQUEST: u32 = 0xea00;
+
+/// PGN of transport protocol connection management messages (TP.CM)
+const PGN_TP_CM: u32 = 0xec00;
+
+/// PGN of transport protocol data transfer messages (TP.DT)
+const PGN_TP_DT: u32 = 0xeb00;
+
+/// PGN of DM1, active DTCs
+const PGN_DM1: u32 = 0xfeca;
+
+/// PGN of DM2, previously active DTCs
+const PGN_DM2: u32 = 0xfecb;
+
+/// TP.CM control byte of a request to send
+const TP_CM_RTS: u8 = 0x10;
+
+/// TP.CM control byte of a clear to send
+const TP_CM_CTS: u8 = 0x11;
+
+/// TP.CM control byte of an end of message acknowledgement
+const TP_CM_END_OF_MSG_ACK: u8 = 0x13;
+
+/// TP.CM control byte of a broadcast announce message
+const TP_CM_BAM: u8 = 0x20;
+
+/// TP.CM control byte of a connection abort
+const TP_CM_ABORT: u8 = 0xff;
+
+/// Time to collect DM1 messages, which are broadcast once per second
+const DM1_WAIT_MILLIS: u64 = 1500;
+
+/// Time to wait for responses to a request, as per J1939-21
+const RESPONSE_WAIT_MILLIS: u64 = 1250;
+
+/// Failure mode identifiers, see J1939-73
+const FMI_DESCRIPTIONS: [&str; 32] = [
+    "Data valid but above normal operational range - most severe level",
+    "Data valid but below normal operational range - most severe level",
+    "Data erratic, intermittent or incorrect",
+    "Voltage above normal, or shorted to high source",
+    "Voltage below normal, or shorted to low source",
+    "Current below normal or open circuit",
+    "Current above normal or grounded circuit",
+    "Mechanical system not responding or out of adjustment",
+    "Abnormal frequency or pulse width or period",
+    "Abnormal update rate",
+    "Abnormal rate of change",
+    "Root cause not known",
+    "Bad intelligent device or component",
+    "Out of calibration",
+    "Special instructions",
+    "Data valid but above normal operating range - least severe level",
+    "Data valid but above normal operating range - moderately severe level",
+    "Data valid but below normal operating range - least severe level",
+    "Data valid but below normal operating range - moderately severe level",
+    "Received network data in error",
+    "Data drifted high",
+    "Data drifted low",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Reserved",
+    "Condition exists",
+];
+
+/**
+ * J1939 diagnostic trouble code, consisting of the suspect parameter number
+ * (SPN), the failure mode identifier (FMI) and the occurrence count (OC).
+ */
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct J1939Dtc {
+    /// Source address of the ECU reporting the DTC
+    pub source: u8,
+    /// Suspect parameter number
+    pub spn: u32,
+    /// Failure mode identifier
+    pub fmi: u8,
+    /// Number of times the failure occurred, 0x7f if not available
+    pub occurrence_count: u8,
+}
+
+impl J1939Dtc {
+    /**
+     * Decode the DTCs of a DM1 or DM2 message sent by the given ECU. The
+     * first two bytes contain the lamp status, followed by 4 bytes per DTC.
+     */
+    pub fn from_dm_data(source: u8, data: &[u8]) -> Result<Vec<Self>, Error> {
+        if data.len() < 6 {
+            return Err(Error::new("Unexpected data length."));
+        }
+
+        let mut dtcs = Vec::new();
+        for chunk in data[2..].chunks_exact(4) {
+            let spn = chunk[0] as u32 + ((chunk[1] as u32) << 8) + ((chunk[2] as u32 >> 5) << 16);
+            let fmi = chunk[2] & 0x1f;
+
+            // A single DTC of all zeros means there are no DTCs, and
+            // messages are padded with 0xff.
+            if (spn == 0 && fmi == 0) || chunk == [0xff; 4] {
+                continue;
+            }
+
+            dtcs.push(Self {
+                source,
+                spn,
+                fmi,
+                occurrence_count: chunk[3] & 0x7f,
+            });
+        }
+
+        Ok(dtcs)
+    }
+
+    /**
+     * Return the description of the failure mode.
+     */
+    pub fn more_info(&self) -> String {
+        format!(
+            "ECU 0x{:02x}, {}, occurrences: {}",
+            self.source,
+            FMI_DESCRIPTIONS[self.fmi as usize],
+            if self.occurrence_count == 0x7f {
+                String::from("n/a")
+            } else {
+                self.occurrence_count.to_string()
+            }
+        )
+    }
+}
+
+impl std::fmt::Display for J1939Dtc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SPN {} FMI {}", self.spn, self.fmi)
+    }
+}
+
+/**
+ * Transport protocol session for receiving a message of more than 8 bytes
+ * from a single ECU.
+ */
+struct TpSession {
+    pgn: u32,
+    size: usize,
+    packets: u8,
+    /// Maximum number of packets the sender allows per CTS
+    max_per_cts: u8,
+    /// Last packet cleared to send by the latest CTS
+    cleared: u8,
+    data: Vec<u8>,
+}
+
+impl TpSession {
+    /**
+     * Return a CTS message for the packets following the ones received so
+     * far, and remember the last packet cleared by it.
+     */
+    fn clear_to_send(&mut self) -> [u8; 8] {
+        let next = (self.data.len() / 7) as u8 + 1;
+        let count = (self.packets - next + 1).min(self.max_per_cts);
+        self.cleared = next + count - 1;
+
+        [
+            TP_CM_CTS,
+            count,
+            next,
+            0xff,
+            0xff,
+            self.pgn as u8,
+            (self.pgn >> 8) as u8,
+            (self.pgn >> 16) as u8,
+        ]
+    }
+}
+
+/// Protocol for reading diagnostics of heavy-duty vehicles via J1939.
+pub struct J1939 {
+    can: CanBus,
+}
+
+impl J1939 {
+    /**
+     * Initialize J1939 on the CAN interface, see [CanBus::init]. The bit rate
+     * defaults to 250kbps.
+     */
+    pub fn init(bit_rate: Option<u64>) -> Result<Self, Error> {
+        let can = CanBus::init(Some(bit_rate.unwrap_or(DEFAULT_BIT_RATE)))?;
+
+        // J1939 only uses 29-bit identifiers.
+        can.socket.set_filter(&[socketcan::CANFilter::new(
+            socketcan::EFF_FLAG,
+            socketcan::EFF_FLAG,
+        )?])?;
+
+        Ok(Self { can })
+    }
+
+    /**
+     * Read the active DTCs of all ECUs from their DM1 broadcasts.
+     */
+    pub fn read_active_dtcs(&mut self) -> Result<Vec<J1939Dtc>, Error> {
+        let messages = self.receive(PGN_DM1, DM1_WAIT_MILLIS)?;
+        Self::decode_dtcs(messages)
+    }
+
+    /**
+     * Request and read the previously active DTCs of all ECUs (DM2).
+     */
+    pub fn read_previously_active_dtcs(&mut self) -> Result<Vec<J1939Dtc>, Error> {
+        self.request(PGN_DM2, GLOBAL_ADDRESS)?;
+        let messages = self.receive(PGN_DM2, RESPONSE_WAIT_MILLIS)?;
+        Self::decode_dtcs(messages)
+    }
+
+    /**
+     * Decode the DTCs of DM1/DM2 messages, ordered by ECU.
+     */
+    fn decode_dtcs(messages: HashMap<u8, Vec<u8>>) -> Result<Vec<J1939Dtc>, Error> {
+        let mut sources: Vec<&u8> = messages.keys().collect();
+        sources.sort();
+
+        let mut dtcs = Vec::new();
+        for source in sources {
+            dtcs.extend(J1939Dtc::from_dm_data(*source, &messages[source])?);
+        }
+
+        Ok(dtcs)
+    }
+
+    /**
+     * Build the 29-bit CAN identifier of a message with the given priority,
+     * PGN, and destination address.
+     */
+    fn identifier(priority: u8, pgn: u32, destination: u8) -> u32 {
+        let mut id = ((priority as u32) << 26) | (pgn << 8) | TESTER_ADDRESS as u32;
+
+        // For PDU1 messages, the PDU specific byte holds the destination.
+        if (pgn >> 8) & 0xff < 0xf0 {
+            id = (id & !0xff00) | ((destination as u32) << 8);
+        }
+
+        id
+    }
+
+    /**
+     * Return the PGN, destination and source address of a 29-bit CAN
+     * identifier.
+     */
+    fn parse_identifier(id: u32) -> (u32, u8, u8) {
+        let pgn = (id >> 8) & 0x3ffff;
+        let source = (id & 0xff) as u8;
+
+        if (pgn >> 8) & 0xff < 0xf0 {
+            (pgn & 0x3ff00, (pgn & 0xff) as u8, source)
+        } else {
+            (pgn, GLOBAL_ADDRESS, source)
+        }
+    }
+
+    /**
+     * Send a single frame with the given priority, PGN and destination.
+     */
+    fn send(&mut self, priority: u8, pgn: u32, destination: u8, data: &[u8]) -> Result<(), Error> {
+        let id = Self::identifier(priority, pgn, destination);
+        let frame = socketcan::CANFrame::new(id, data, false, false)?;
+        trace!(target: FRAME_LOG_TARGET, "SEND {:02X}", frame);
+        self.can
+            .socket
+            .write_frame_insist(&frame)
+            .map_err(|e| Error::connection_lost(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /**
+     * Request the given PGN from the given ECU, or all of them.
+     */
+    fn request(&mut self, pgn: u32, destination: u8) -> Result<(), Error> {
+        let data = [pgn as u8, (pgn >> 8) as u8, (pgn >> 16) as u8];
+        self.send(6, PGN_REQUEST, destination, &data)
+    }
+
+    /**
+     * Collect the messages with the given PGN sent by any ECU within the
+     * given time, reassembling messages sent using the transport protocol.
+     * If an ECU sends the message more than once, the last one is used.
+     */
+    fn receive(&mut self, pgn: u32, wait_millis: u64) -> Result<HashMap<u8, Vec<u8>>, Error> {
+        let mut messages = HashMap::new();
+        let mut sessions: HashMap<u8, TpSession> = HashMap::new();
+
+        let running = running_flag();
+        let start = Instant::now();
+
+        while start.elapsed() < Duration::from_millis(wait_millis) {
+            if !running.load(Ordering::SeqCst) {
+                return Err(Error::new("Interrupted."));
+            }
+
+            let frame = match self.can.socket.read_frame() {
+                Ok(f) => f,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
+                Err(e) => {
+                    return Err(Error::connection_lost(e.to_string()));
+                }
+            };
+
+            if frame.is_error() {
+                match self.can.handle_error_frame(&frame) {
+                    Some(e) => return Err(e),
+                    None => continue,
+                }
+            }
+
+            let (frame_pgn, destination, source) = Self::parse_identifier(frame.id());
+            if destination != GLOBAL_ADDRESS && destination != TESTER_ADDRESS {
+                continue;
+            }
+
+            let data = frame.data();
+            if frame_pgn == pgn {
+                trace!(target: FRAME_LOG_TARGET, "RECV {:02X}", frame);
+                messages.insert(source, data.to_vec());
+            } else if frame_pgn == PGN_TP_CM && data.len() == 8 {
+                trace!(target: FRAME_LOG_TARGET, "RECV {:02X}", frame);
+                let session_pgn =
+                    data[5] as u32 + ((data[6] as u32) << 8) + ((data[7] as u32) << 16);
+                if session_pgn != pgn {
+                    continue;
+                }
+
+                match data[0] {
+                    TP_CM_BAM | TP_CM_RTS => {
+                        let mut session = TpSession {
+                            pgn,
+                            size: data[1] as usize + ((data[2] as usize) << 8),
+                            packets: data[3],
+                            // 0xff means no limit, for BAM this is unused.
+                            max_per_cts: data[4].max(1),
+                            cleared: data[3],
+                            data: Vec::new(),
+                        };
+
+                        if session.packets == 0 {
+                            continue;
+                        }
+
+                        // Destination-specific transfers have to be
+                        // acknowledged, as many packets at once as the
+                        // sender allows.
+                        if data[0] == TP_CM_RTS {
+                            let cts = session.clear_to_send();
+                            self.send(7, PGN_TP_CM, source, &cts)?;
+                        }
+
+                        sessions.insert(source, session);
+                    }
+                    TP_CM_ABORT => {
+                        warn!("ECU 0x{:02x} aborted transfer.", source);
+                        sessions.remove(&source);
+                    }
+                    _ => {}
+                }
+            } else if frame_pgn == PGN_TP_DT && data.len() == 8 {
+                let session = match sessions.get_mut(&source) {
+                    Some(s) => s,
+                    None => {
+                        continue;
+                    }
+                };
+
+                trace!(target: FRAME_LOG_TARGET, "RECV {:02X}", frame);
+
+                if data[0] as usize != session.data.len() / 7 + 1 {
+                    warn!(
+                        "Unexpected TP.DT sequence number from ECU 0x{:02x}.",
+                        source
+                    );
+                    sessions.remove(&source);
+                    continue;
+                }
+
+                session.data.extend(&data[1..]);
+
+                if data[0] == session.cleared && data[0] < session.packets {
+                    let cts = session.clear_to_send();
+                    self.send(7, PGN_TP_CM, source, &cts)?;
+                    continue;
+                }
+
+                if data[0] == session.packets {
+                    let mut session = sessions.remove(&source).unwrap();
+                    session.data.truncate(session.size);
+
+                    if destination == TESTER_ADDRESS {
+                        let size = session.size as u16;
+                        let ack = [
+                            TP_CM_END_OF_MSG_ACK,
+                            size as u8,
+                            (size >> 8) as u8,
+                            session.packets,
+                            0xff,
+                            session.pgn as u8,
+                            (session.pgn >> 8) as u8,
+                            (session.pgn >> 16) as u8,
+                        ];
+                        self.send(7, PGN_TP_CM, source, &ack)?;
+                    }
+
+                    debug!("Received {} bytes from ECU 0x{:02x}.", session.size, source);
+                    messages.insert(source, session.data);
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clear_to_send_limited() {
+        let mut session = TpSession {
+            pgn: PGN_DM1,
+            size: 30,
+            packets: 5,
+            max_per_cts: 2,
+            cleared: 5,
+            data: Vec::new(),
+        };
+
+        let cts = session.clear_to_send();
+        assert_eq!(&cts[..3], &[TP_CM_CTS, 2, 1]);
+        assert_eq!(&cts[5..], &[0xca, 0xfe, 0x00]);
+        assert_eq!(session.cleared, 2);
+
+        session.data.extend(&[0; 14]);
+        assert_eq!(&session.clear_to_send()[..3], &[TP_CM_CTS, 2, 3]);
+        assert_eq!(session.cleared, 4);
+
+        session.data.extend(&[0; 14]);
+        assert_eq!(&session.clear_to_send()[..3], &[TP_CM_CTS, 1, 5]);
+        assert_eq!(session.cleared, 5);
+    }
+
+    #[test]
+    fn clear_to_send_unlimited() {
+        let mut session = TpSession {
+            pgn: PGN_DM1,
+            size: 30,
+            packets: 5,
+            max_per_cts: 0xff,
+            cleared: 5,
+            data: Vec::new(),
+        };
+
+        assert_eq!(&session.clear_to_send()[..3], &[TP_CM_CTS, 5, 1]);
+        assert_eq!(session.cleared, 5);
+    }
+}
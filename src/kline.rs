@@ -1,10 +1,10 @@
 //! Physical layer implementation for the K line
 
 use std::io::{Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::thread::sleep;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 use gpio_cdev::{Chip, LineHandle, LineRequestFlags};
 use log::{debug, info, warn};
@@ -17,8 +17,138 @@ use crate::misc::*;
 /// Baud rate to use for initialization
 const INIT_BAUD_RATE: u64 = 5;
 
-/// Delay before writing byte to K line
-const WRITE_DELAY_MICROS: u64 = 5000;
+/// Default delay before writing byte to K line
+pub const WRITE_DELAY_MICROS: u64 = 5000;
+
+/// Time to wait for a byte to be received
+const READ_TIMEOUT_MILLIS: u64 = 1000;
+
+/// Pins to use instead of the BeagleBone Blue's, see [set_default_pins]
+static DEFAULT_PINS: Mutex<Option<PinMap>> = Mutex::new(None);
+
+/// Pins switched to another mode since the start, see [reset_pin_modes]
+static CHANGED_PINS: Mutex<Option<PinMap>> = Mutex::new(None);
+
+/// Write delay of newly initialized K lines, see [set_default_write_delay]
+static DEFAULT_WRITE_DELAY: AtomicU64 = AtomicU64::new(WRITE_DELAY_MICROS);
+
+/**
+ * Set the delay before writing each byte used by newly initialized K lines,
+ * instead of [WRITE_DELAY_MICROS]. Lowering this speeds up communication
+ * with fast ECUs, but breaks it with sluggish ones.
+ */
+pub fn set_default_write_delay(micros: u64) {
+    DEFAULT_WRITE_DELAY.store(micros, Ordering::SeqCst);
+}
+
+/**
+ * Set the pins used by default, i.e. returned by [PinMap::default].
+ */
+pub fn set_default_pins(pins: PinMap) {
+    *DEFAULT_PINS.lock().unwrap() = Some(pins);
+}
+
+/**
+ * Switch the pins back to UART mode if they were switched before, e.g. after
+ * the initialization failed halfway through. Failures are only logged, since
+ * this is called on exit.
+ */
+pub fn reset_pin_modes() {
+    if let Some(pins) = CHANGED_PINS.lock().unwrap().take() {
+        if let Err(e) = pins.set_pin_mode(PinMode::Uart) {
+            warn!("Failed to reset pin modes: {}", e);
+        }
+    }
+}
+
+/**
+ * Return the given error of reading or writing the K line as a lost
+ * connection, e.g. the ECU stopped responding because the session ended.
+ */
+fn port_error(error: std::io::Error) -> Error {
+    Error::connection_lost(error.to_string())
+}
+
+/**
+ * Board-specific pins and devices used for the K line. The default is the
+ * UART1 bus of the BeagleBone Blue, unless set otherwise via
+ * [set_default_pins].
+ */
+#[derive(Clone, Debug)]
+pub struct PinMap {
+    /// Pin multiplexer state files of the tx/rx pins, for boards that need
+    /// the pins switched between UART and GPIO mode
+    pub pinmux_paths: Vec<String>,
+    /// GPIO chip device of the tx/rx pins
+    pub gpio_chip: String,
+    /// GPIO line of the tx pin
+    pub tx_line: u32,
+    /// GPIO line of the rx pin
+    pub rx_line: u32,
+    /// Serial device of the UART
+    pub serial_device: String,
+}
+
+impl PinMap {
+    /**
+     * Pins of the UART1 bus on the BeagleBone Blue, P9_24 (rx) and P9_26
+     * (tx).
+     */
+    pub fn beaglebone_blue() -> Self {
+        Self {
+            pinmux_paths: vec![
+                String::from("/sys/devices/platform/ocp/ocp:P9_24_pinmux/state"),
+                String::from("/sys/devices/platform/ocp/ocp:P9_26_pinmux/state"),
+            ],
+            gpio_chip: String::from("/dev/gpiochip0"),
+            tx_line: 15,
+            rx_line: 14,
+            serial_device: String::from("/dev/ttyO1"),
+        }
+    }
+
+    /**
+     * Set the pin multiplexer mode of the tx/rx pins, if the board needs it.
+     */
+    pub fn set_pin_mode(&self, mode: PinMode) -> Result<(), Error> {
+        if !self.pinmux_paths.is_empty() {
+            *CHANGED_PINS.lock().unwrap() = Some(self.clone());
+        }
+
+        for path in self.pinmux_paths.iter() {
+            set_pin_mode(path, mode)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PinMap {
+    fn default() -> Self {
+        DEFAULT_PINS
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(Self::beaglebone_blue)
+    }
+}
+
+/**
+ * Details about the K line initialization, e.g. for displaying the
+ * connection details.
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InitReport {
+    /// Baud rate measured from the sync byte
+    pub measured_baud: u64,
+    /// Baud rate used for communication, either given or the known baud rate
+    /// nearest to the measured one
+    pub chosen_baud: u64,
+    /// Key bytes sent by the ECU after the sync byte
+    pub key_bytes: Vec<u8>,
+    /// Identifying data sent by the ECU after initialization, if any
+    pub ecu_identification: Vec<u8>,
+}
 
 /// General physical layer implementation for various K line protocols.
 pub struct KLine {
@@ -26,11 +156,15 @@ pub struct KLine {
     pub port: serial::unix::TTYPort,
     /// Given or determined baud rate
     pub baud_rate: u64,
+    /// Baud rate measured from the sync byte during initialization
+    pub measured_baud_rate: u64,
+    /// Delay before writing each byte in microseconds
+    pub write_delay: u64,
 }
 
 impl KLine {
     /**
-     * Initialize the K line on the UART1 bus by addressing the given ECU.
+     * Initialize the K line on the given pins by addressing the given ECU.
      *
      * If the baud rate is not given, it will be determined automatically from
      * the sync byte. Because of the extremely low baud rate used for the
@@ -40,9 +174,13 @@ impl KLine {
      * neither is the initialization on the L line in parallel, as is
      * used optionally by ISO 9141, and KWP2000.
      */
-    pub fn init(init_address: u8, baud_rate: Option<u64>) -> Result<Self, Error> {
+    pub fn init_with_pins(
+        pins: &PinMap,
+        init_address: u8,
+        baud_rate: Option<u64>,
+    ) -> Result<Self, Error> {
         // Initialize communication manually, in GPIO mode
-        let (tx, rx) = Self::initialize_gpio()?;
+        let (tx, rx) = Self::initialize_gpio(pins)?;
 
         // Guarantee that the K line is high for a while before we pull it down
         sleep(Duration::from_millis(300));
@@ -50,18 +188,26 @@ impl KLine {
         // Write 0x01 using 7O1 UART, at 5 baud
         Self::write_byte_software(&tx, init_address, 7, true, INIT_BAUD_RATE)?;
 
-        // Manually read sync byte (0x55) to figure out main baud rate
+        // Manually read sync byte (0x55) to figure out main baud rate. Each of
+        // its bits causes an edge, so record the time of all of them.
         busy_wait_until(&rx, 0, 500_000 as u64)?;
-        let reference = SystemTime::now();
+        let reference = Instant::now();
+        let mut edges: Vec<u128> = vec![0];
+
         busy_wait_until(&rx, 1, 500_000 as u64)?;
+        edges.push(reference.elapsed().as_micros());
 
         for _i in 0..4 {
             busy_wait_until(&rx, 0, 500_000 as u64)?;
+            edges.push(reference.elapsed().as_micros());
             busy_wait_until(&rx, 1, 500_000 as u64)?;
+            edges.push(reference.elapsed().as_micros());
         }
 
-        let measured = 1_000_000 / (reference.elapsed().unwrap().as_micros() / 9);
-        let baud = baud_rate.unwrap_or(Self::nearest_baud_rate(measured as u64));
+        debug!("Sync byte edges (us): {:?}", edges);
+
+        let measured = (1_000_000.0 / Self::bit_period(&edges)) as u64;
+        let baud = baud_rate.unwrap_or(Self::nearest_baud_rate(measured));
 
         if baud_rate.is_none() {
             info!("Measured baud rate: {}", measured);
@@ -70,26 +216,34 @@ impl KLine {
 
         // Switch to proper UART (8N1) for remainder of communication
         Ok(Self {
-            port: Self::initialize_uart(baud)?,
+            port: Self::initialize_uart(pins, baud)?,
             baud_rate: baud,
+            measured_baud_rate: measured,
+            write_delay: DEFAULT_WRITE_DELAY.load(Ordering::SeqCst),
         })
     }
 
+    /**
+     * Return the baud rate details of the initialization. Key bytes and ECU
+     * identification are left to the protocols.
+     */
+    pub fn init_report(&self) -> InitReport {
+        InitReport {
+            measured_baud: self.measured_baud_rate,
+            chosen_baud: self.baud_rate,
+            ..InitReport::default()
+        }
+    }
+
     /**
      * Continuously transmit or receive data via the UART1 bus using the given
      * baud rate. This is useful for low-level hardware testing, or hooking up
      * an oscilloscope.
      */
     pub fn test_hardware(tx: bool, baud_rate: u64) -> Result<(), Error> {
-        let mut port = Self::initialize_uart(baud_rate)?;
-
-        let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
+        let mut port = Self::initialize_uart(&PinMap::default(), baud_rate)?;
 
-        ctrlc::set_handler(move || {
-            r.store(false, Ordering::SeqCst);
-        })
-        .unwrap();
+        let running = running_flag();
 
         let mut buffer = vec![0];
         loop {
@@ -112,6 +266,26 @@ impl KLine {
         Ok(())
     }
 
+    /**
+     * Estimate the bit period in microseconds from the times of consecutive
+     * edges one bit apart, using a least squares fit. This is less sensitive
+     * to scheduling jitter than timing just the first and last edge.
+     */
+    fn bit_period(edges: &[u128]) -> f64 {
+        let n = edges.len() as f64;
+        let mean_index = (n - 1.0) / 2.0;
+        let mean_time = edges.iter().sum::<u128>() as f64 / n;
+
+        let mut covariance = 0.0;
+        let mut variance = 0.0;
+        for (i, t) in edges.iter().enumerate() {
+            covariance += (i as f64 - mean_index) * (*t as f64 - mean_time);
+            variance += (i as f64 - mean_index).powi(2);
+        }
+
+        covariance / variance
+    }
+
     /**
      * Determine the known baud rate closest to the one that was measured.
      */
@@ -122,33 +296,31 @@ impl KLine {
     }
 
     /**
-     * Set the UART1 tx/rx pins' pin multiplexer state to GPIO, and initialize
-     * the LineHandles.
+     * Set the tx/rx pins' pin multiplexer state to GPIO, and initialize the
+     * LineHandles.
      */
-    pub fn initialize_gpio() -> Result<(LineHandle, LineHandle), Error> {
-        set_pin_mode(9, 24, PinMode::Gpio)?;
-        set_pin_mode(9, 26, PinMode::Gpio)?;
+    pub fn initialize_gpio(pins: &PinMap) -> Result<(LineHandle, LineHandle), Error> {
+        pins.set_pin_mode(PinMode::Gpio)?;
 
-        let mut chip = Chip::new("/dev/gpiochip0")?;
+        let mut chip = Chip::new(&pins.gpio_chip)?;
         let tx = chip
-            .get_line(15)?
+            .get_line(pins.tx_line)?
             .request(LineRequestFlags::OUTPUT, 1, "k-tx")?;
         let rx = chip
-            .get_line(14)?
+            .get_line(pins.rx_line)?
             .request(LineRequestFlags::INPUT, 0, "k-rx")?;
 
         Ok((tx, rx))
     }
 
     /**
-     * Set the UART1 tx/rx pins' pin multiplexer state to UART, and initialize
-     * the UART bus with the given baud rate.
+     * Set the tx/rx pins' pin multiplexer state to UART, and initialize the
+     * UART bus with the given baud rate.
      */
-    pub fn initialize_uart(baud_rate: u64) -> Result<serial::unix::TTYPort, Error> {
-        set_pin_mode(9, 24, PinMode::Uart)?;
-        set_pin_mode(9, 26, PinMode::Uart)?;
+    pub fn initialize_uart(pins: &PinMap, baud_rate: u64) -> Result<serial::unix::TTYPort, Error> {
+        pins.set_pin_mode(PinMode::Uart)?;
 
-        let mut port = serial::open("/dev/ttyO1")?;
+        let mut port = serial::open(&pins.serial_device)?;
         let mut settings = port.read_settings()?;
         settings.set_baud_rate(serial::BaudOther(baud_rate as usize))?;
         settings.set_char_size(serial::Bits8);
@@ -156,7 +328,7 @@ impl KLine {
         settings.set_stop_bits(serial::Stop1);
         settings.set_flow_control(serial::FlowNone);
         port.write_settings(&settings)?;
-        port.set_timeout(Duration::from_millis(1000))?;
+        port.set_timeout(Duration::from_millis(READ_TIMEOUT_MILLIS))?;
 
         Ok(port)
     }
@@ -174,7 +346,7 @@ impl KLine {
         parity: bool,
         baud: u64,
     ) -> Result<(), Error> {
-        let start = SystemTime::now();
+        let start = Instant::now();
 
         let bits: Vec<u8> = (0..char_size).map(|i| (value & (1 << i)) >> i).collect();
         let delay: u64 = 1_000_000 / baud;
@@ -203,7 +375,7 @@ impl KLine {
      */
     pub fn read_byte(&mut self, complement: bool) -> Result<u8, Error> {
         let mut buffer: Vec<u8> = vec![0];
-        self.port.read_exact(&mut buffer)?;
+        self.port.read_exact(&mut buffer).map_err(port_error)?;
 
         if complement {
             self.write_byte(0xff - buffer[0], false)?;
@@ -212,25 +384,44 @@ impl KLine {
         Ok(buffer[0])
     }
 
+    /**
+     * Read a single byte, or return None if none was received within the
+     * given time, instead of the usual read timeout.
+     */
+    pub fn read_byte_within(&mut self, timeout: Duration) -> Result<Option<u8>, Error> {
+        let mut buffer: Vec<u8> = vec![0];
+
+        self.port.set_timeout(timeout)?;
+        let result = self.port.read_exact(&mut buffer);
+        self.port
+            .set_timeout(Duration::from_millis(READ_TIMEOUT_MILLIS))?;
+
+        match result {
+            Ok(()) => Ok(Some(buffer[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(port_error(e)),
+        }
+    }
+
     /**
      * Write a single byte, optionally expect a complement byte.
      */
     pub fn write_byte(&mut self, value: u8, complement: bool) -> Result<(), Error> {
         // We need to add a small delay before writing, otherwise communication
         // might not work. Presumably the ECU is not fast enough.
-        busy_wait(SystemTime::now(), WRITE_DELAY_MICROS);
+        busy_wait(Instant::now(), self.write_delay);
 
         let mut buffer: Vec<u8> = vec![0];
 
-        let start = SystemTime::now();
-        self.port.write(&[value])?;
+        let start = Instant::now();
+        self.port.write(&[value]).map_err(port_error)?;
 
         // read back value
-        self.port.read_exact(&mut buffer)?;
+        self.port.read_exact(&mut buffer).map_err(port_error)?;
         busy_wait(start, 10 * 1_000_000 / self.baud_rate);
 
         if complement {
-            self.port.read_exact(&mut buffer)?;
+            self.port.read_exact(&mut buffer).map_err(port_error)?;
             if buffer[0] != (0xff - value) {
                 warn!("Invalid complement received.");
             }
@@ -1,17 +1,24 @@
 //! Protocol implementation for KWP1281
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use log::{debug, info};
+use log::{debug, info, trace, warn};
 
 use crate::diagnose::*;
 use crate::error::*;
 use crate::kline::*;
 use crate::misc::*;
 
+/// Default time budget for the ECU to finish sending a sequence of blocks,
+/// e.g. its identification after initialization or its DTCs
+pub const DEFAULT_BLOCK_BUDGET_MILLIS: u64 = 5000;
+
+/// Idle time after which ACK blocks are exchanged to keep the session alive.
+/// ECUs end the session after about a second without blocks.
+const KEEPALIVE_INTERVAL_MILLIS: u64 = 500;
+
 /// Enum of KWP1281 block types (also referred to as block titles online).
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum Kwp1281BlockType {
@@ -88,6 +95,10 @@ struct Kwp1281Block {
 pub struct Kwp1281 {
     kline: KLine,
     block_counter: u8,
+    block_budget: Duration,
+    request_sent: Option<Instant>,
+    last_block: Instant,
+    key_bytes: Vec<u8>,
     /// Identifying data returned by the ECU after initialization
     pub ecu_data: Vec<u8>,
 }
@@ -108,11 +119,45 @@ impl Kwp1281 {
      * byte if no specific baud rate is given.
      */
     pub fn init(target_address: u8, baud_rate: Option<u64>) -> Result<Self, Error> {
-        let kline = KLine::init(target_address, baud_rate)?;
+        Self::init_with_block_budget(
+            target_address,
+            baud_rate,
+            Duration::from_millis(DEFAULT_BLOCK_BUDGET_MILLIS),
+        )
+    }
+
+    /**
+     * Initialize the KWP1281 protocol, see [Kwp1281::init], allowing the ECU
+     * to take up to the given time for sending a sequence of blocks. Chatty
+     * ECUs may need more than the default.
+     */
+    pub fn init_with_block_budget(
+        target_address: u8,
+        baud_rate: Option<u64>,
+        block_budget: Duration,
+    ) -> Result<Self, Error> {
+        Self::init_with_pins(&PinMap::default(), target_address, baud_rate, block_budget)
+    }
+
+    /**
+     * Initialize the KWP1281 protocol on the given pins, see
+     * [Kwp1281::init_with_block_budget].
+     */
+    pub fn init_with_pins(
+        pins: &PinMap,
+        target_address: u8,
+        baud_rate: Option<u64>,
+        block_budget: Duration,
+    ) -> Result<Self, Error> {
+        let kline = KLine::init_with_pins(pins, target_address, baud_rate)?;
 
         let mut kwp = Self {
             kline,
             block_counter: 0,
+            block_budget,
+            request_sent: None,
+            last_block: Instant::now(),
+            key_bytes: Vec::new(),
             ecu_data: Vec::new(),
         };
 
@@ -122,6 +167,7 @@ impl Kwp1281 {
         let kb1 = kwp.kline.read_byte(false)?;
         let kb2 = kwp.kline.read_byte(false)?;
         debug!("Key Bytes: {:02x?} {:02x?}", kb1, kb2);
+        kwp.key_bytes = vec![kb1, kb2];
 
         if kb1 != 0x01 || kb2 != 0x8a {
             return Err(Error::new("Unexpected key byte."));
@@ -132,7 +178,8 @@ impl Kwp1281 {
         // Initialization is over, ECU will now send some data about itself.
         // We will have to wait for that to finish while ACKing blocks.
         let mut ecu_done_yapping = false;
-        for _i in 0..10 {
+        let start = Instant::now();
+        while start.elapsed() < kwp.block_budget {
             let block = kwp.read_block()?;
             if block.block_type == Kwp1281BlockType::Ack {
                 ecu_done_yapping = true;
@@ -153,24 +200,30 @@ impl Kwp1281 {
         Ok(kwp)
     }
 
+    /**
+     * Return details about the initialization, such as the baud rate and the
+     * key bytes.
+     */
+    pub fn init_report(&self) -> InitReport {
+        InitReport {
+            key_bytes: self.key_bytes.clone(),
+            ecu_identification: self.ecu_data.clone(),
+            ..self.kline.init_report()
+        }
+    }
+
     /**
      * Run a crude car simulator using the given baud rate. This simulator can
      * be used for testing the logic level conversion hardware using two BBBs.
      */
     pub fn run_simulator(baud_rate: u64) -> Result<(), Error> {
-        let running = Arc::new(AtomicBool::new(true));
-        let r = running.clone();
-
-        ctrlc::set_handler(move || {
-            r.store(false, Ordering::SeqCst);
-        })
-        .unwrap();
+        let running = running_flag();
 
         while running.load(Ordering::SeqCst) {
             info!("Waiting for connections...");
 
             {
-                let (_tx, rx) = KLine::initialize_gpio()?;
+                let (_tx, rx) = KLine::initialize_gpio(&PinMap::default())?;
 
                 while running.load(Ordering::SeqCst) {
                     if let Err(_) = busy_wait_until(&rx, 0, 500_000 as u64) {
@@ -197,8 +250,13 @@ impl Kwp1281 {
                 info!("Connection received: {:?}", bits);
             }
 
-            let port = KLine::initialize_uart(baud_rate)?;
-            let mut kline = KLine { port, baud_rate };
+            let port = KLine::initialize_uart(&PinMap::default(), baud_rate)?;
+            let mut kline = KLine {
+                port,
+                baud_rate,
+                measured_baud_rate: baud_rate,
+                write_delay: WRITE_DELAY_MICROS,
+            };
 
             kline.write_byte(0x55, false)?;
             kline.write_byte(0x01, false)?;
@@ -207,6 +265,10 @@ impl Kwp1281 {
             let mut kwp = Kwp1281 {
                 kline,
                 block_counter: 0,
+                block_budget: Duration::from_millis(DEFAULT_BLOCK_BUDGET_MILLIS),
+                request_sent: None,
+                last_block: Instant::now(),
+                key_bytes: Vec::new(),
                 ecu_data: Vec::new(),
             };
 
@@ -258,7 +320,9 @@ impl Kwp1281 {
      * Write a given block to the K line. Does not wait for acknowledgement.
      */
     fn write_block(&mut self, block: Kwp1281Block) -> Result<(), Error> {
-        debug!("SEND {:02x} {:02x?}", block.block_type, &block.data);
+        trace!(target: FRAME_LOG_TARGET, "SEND {:02x} {:02x?}", block.block_type, &block.data);
+        self.request_sent = Some(Instant::now());
+        self.last_block = Instant::now();
 
         self.block_counter = self.block_counter.wrapping_add(1);
         let length = block.data.len() + 3;
@@ -293,7 +357,11 @@ impl Kwp1281 {
 
         self.kline.read_byte(false)?;
 
-        debug!("RECV {:02x} {:02x?}", block_type, data);
+        trace!(target: FRAME_LOG_TARGET, "RECV {:02x} {:02x?}", block_type, data);
+
+        if let Some(sent) = self.request_sent.take() {
+            record_latency(sent.elapsed());
+        }
 
         Ok(Kwp1281Block {
             block_type: block_type.into(),
@@ -376,8 +444,26 @@ impl Kwp1281 {
     }
 }
 
+/**
+ * Parse the DTCs of a GetDtcs response block, consisting of a 2-byte code and
+ * a status byte each.
+ */
+fn parse_dtcs(data: &[u8]) -> Vec<DiagnosticTroubleCode> {
+    let chunks = data.chunks_exact(3);
+    if !chunks.remainder().is_empty() {
+        warn!("Ignoring trailing DTC data: {:02x?}", chunks.remainder());
+    }
+
+    chunks
+        .map(|chunk| {
+            let code = ((chunk[0] as u16) << 8) + (chunk[1] as u16);
+            DiagnosticTroubleCode::Oem(code, chunk[2])
+        })
+        .collect()
+}
+
 impl Diagnose for Kwp1281 {
-    fn read_dtcs(&mut self, _pending: bool) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+    fn read_dtcs(&mut self, _category: DtcCategory) -> Result<Vec<DiagnosticTroubleCode>, Error> {
         let mut dtcs = Vec::new();
 
         self.write_block(Kwp1281Block {
@@ -385,26 +471,27 @@ impl Diagnose for Kwp1281 {
             data: Vec::new(),
         })?;
 
-        for i in 0..10 {
+        let start = Instant::now();
+        let mut first = true;
+        while start.elapsed() < self.block_budget {
             let block = self.read_block()?;
             if block.block_type == Kwp1281BlockType::Ack {
                 return Ok(dtcs);
             }
 
-            if i == 0 && block.data == &[0xff, 0xff, 0x88] {
+            if first && block.data == &[0xff, 0xff, 0x88] {
                 return Ok(dtcs);
             }
 
-            for chunk in block.data.chunks(3) {
-                let code = ((chunk[0] as u16) << 8) + (chunk[1] as u16);
-                let status = chunk[2];
-                dtcs.push(DiagnosticTroubleCode::Oem(code, status));
-            }
+            dtcs.extend(parse_dtcs(&block.data));
 
             self.write_ack()?;
+            first = false;
         }
 
-        return Err(Error::new("Timeout waiting for DTCs to finish."));
+        return Err(Error::connection_lost(
+            "Timeout waiting for DTCs to finish.",
+        ));
     }
 
     fn clear_dtcs(&mut self) -> Result<(), Error> {
@@ -440,4 +527,47 @@ impl Diagnose for Kwp1281 {
             Err(Error::new("Unexpected response to ReadData command."))
         }
     }
+
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        // Any other block keeps the session alive as well.
+        if self.last_block.elapsed() < Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS) {
+            return Ok(());
+        }
+
+        self.write_ack()?;
+
+        let response = self.read_block()?;
+
+        if response.block_type == Kwp1281BlockType::Ack {
+            Ok(())
+        } else {
+            Err(Error::new("Unexpected response to Ack command."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dtcs() {
+        assert_eq!(
+            parse_dtcs(&[0x46, 0x5b, 0x23, 0x04, 0x69, 0x1d]),
+            vec![
+                DiagnosticTroubleCode::Oem(0x465b, 0x23),
+                DiagnosticTroubleCode::Oem(0x0469, 0x1d),
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_length_dtcs() {
+        // The incomplete trailing DTC is ignored.
+        assert_eq!(
+            parse_dtcs(&[0x46, 0x5b, 0x23, 0x04, 0x69]),
+            vec![DiagnosticTroubleCode::Oem(0x465b, 0x23)]
+        );
+        assert!(parse_dtcs(&[0x46]).is_empty());
+    }
 }
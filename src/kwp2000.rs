@@ -1,23 +1,135 @@
 //! Protocol implementation for KWP2000 / ISO 14230
 
 use std::num::Wrapping;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 
-use log::debug;
+use log::{debug, trace, warn};
 
 use crate::diagnose::*;
 use crate::error::*;
 use crate::kline::*;
 use crate::misc::*;
+use crate::seedkey::*;
 
 /// Delay between ECU response and next tester request (P4)
 const BLOCK_DELAY_MICROS: u64 = 60_000;
 
+/// Diagnostic session started after initialization. This might be
+/// manufacturer specific and will have to be modified.
+const DIAGNOSTIC_SESSION: u8 = 0x89;
+
+/// Default interval of testerPresent requests keeping the session alive
+pub const KEEPALIVE_INTERVAL_MILLIS: u64 = 2000;
+
+/// Maximum number of data bytes in a single block on the K line
+const MAX_BLOCK_DATA: usize = 63;
+
+/// Negative response code indicating the ECU needs more time to respond
+const NRC_RESPONSE_PENDING: u8 = 0x78;
+
+/// Maximum number of blocks read while waiting for the response to the
+/// request stopping periodic transmission
+const MAX_STOP_BLOCKS: usize = 32;
+
+/// Maximum time between bytes of an ECU response (P1 max)
+const INTER_BYTE_TIMEOUT_MILLIS: u64 = 20;
+
+/// Status parameter of readDiagnosticTroubleCodesByStatus used by default
+pub const DTC_STATUS_DEFAULT: u8 = 0x02;
+
+/// Group parameter of readDiagnosticTroubleCodesByStatus for all DTC groups
+pub const DTC_GROUP_ALL: u16 = 0xff00;
+
+/// Status parameter of readDiagnosticTroubleCodesByStatus to request all
+/// stored DTCs, regardless of their status
+pub const DTC_STATUS_ANY: u8 = 0x00;
+
+/// Group parameter used together with [DTC_STATUS_ANY]
+pub const DTC_GROUP_ANY: u16 = 0x0000;
+
+/// Rate at which the ECU pushes data using readDataByPeriodicIdentifier
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransmissionRate {
+    /// Manufacturer specific slow rate
+    Slow,
+    /// Manufacturer specific medium rate
+    Medium,
+    /// Manufacturer specific fast rate
+    Fast,
+}
+
+impl TransmissionRate {
+    /**
+     * Return the transmission mode parameter of the request.
+     */
+    fn mode(&self) -> u8 {
+        match self {
+            Self::Slow => 0x01,
+            Self::Medium => 0x02,
+            Self::Fast => 0x03,
+        }
+    }
+}
+
+/**
+ * Check that the given startDiagnosticSession response confirms the given
+ * session.
+ */
+fn check_session_response(data: &[u8], session: u8) -> Result<(), Error> {
+    if data.get(0) == Some(&0x7f) && data.len() >= 3 {
+        return Err(Error::new(format!(
+            "ECU rejected diagnostic session 0x{:02x} (0x{:02x}).",
+            session, data[2]
+        )));
+    }
+
+    if data.get(0) != Some(&0x50) || data.get(1) != Some(&session) {
+        return Err(Error::new(format!(
+            "Unexpected response to startDiagnosticSession command: {:02x?}",
+            data
+        )));
+    }
+
+    Ok(())
+}
+
+/**
+ * Parse a readDiagnosticTroubleCodesByStatus response, consisting of the
+ * number of DTCs and a 2-byte code and status byte each.
+ */
+fn parse_dtcs(data: &[u8]) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+    if data.len() < 2 || data[0] != 0x58 {
+        return Err(Error::new("Unexpected response to readDiagnosticTroubleCodesByStatus command."));
+    }
+
+    let chunks = data[2..].chunks_exact(3);
+    if !chunks.remainder().is_empty() {
+        warn!("Ignoring trailing DTC data: {:02x?}", chunks.remainder());
+    }
+
+    let mut dtcs = Vec::with_capacity(data[1] as usize);
+    for chunk in chunks {
+        let code = ((chunk[0] as u16) << 8) + (chunk[1] as u16);
+        let status = chunk[2];
+        dtcs.push(DiagnosticTroubleCode::Kwp2000(code, status));
+    }
+
+    Ok(dtcs)
+}
+
 /// Protocol for talking to the vehicle's K line via KWP2000.
 pub struct Kwp2000 {
     kline: KLine,
     physical_address: u8,
     block_delay: u64,
+    inter_byte_timeout: u64,
+    request_sent: Option<Instant>,
+    last_request: Instant,
+    keepalive_interval: Duration,
+    /// Whether the ECU is pushing data via readDataByPeriodicIdentifier
+    periodic: bool,
+    security_access: bool,
+    key_bytes: Vec<u8>,
 }
 
 impl Kwp2000 {
@@ -31,6 +143,18 @@ impl Kwp2000 {
      * initialization of the L line.
      */
     pub fn init(target_address: u8, baud_rate: Option<u64>, physical_address: Option<u8>) -> Result<Self, Error> {
+        Self::init_with_pins(&PinMap::default(), target_address, baud_rate, physical_address)
+    }
+
+    /**
+     * Initialize the KWP2000 protocol on the given pins, see [Kwp2000::init].
+     */
+    pub fn init_with_pins(
+        pins: &PinMap,
+        target_address: u8,
+        baud_rate: Option<u64>,
+        physical_address: Option<u8>,
+    ) -> Result<Self, Error> {
         // TODO: fast init?
         // TODO: write address to L line as well
 
@@ -45,16 +169,24 @@ impl Kwp2000 {
             }
         });
 
-        let kline = KLine::init(target_address, baud_rate)?;
+        let kline = KLine::init_with_pins(pins, target_address, baud_rate)?;
         let mut kwp = Self {
             kline,
             physical_address,
             block_delay: BLOCK_DELAY_MICROS,
+            inter_byte_timeout: INTER_BYTE_TIMEOUT_MILLIS,
+            request_sent: None,
+            last_request: Instant::now(),
+            keepalive_interval: Duration::from_millis(KEEPALIVE_INTERVAL_MILLIS),
+            periodic: false,
+            security_access: false,
+            key_bytes: Vec::new(),
         };
 
         let kb1 = kwp.kline.read_byte(false)?;
         let kb2 = kwp.kline.read_byte(false)?;
         debug!("Key Bytes: {:02x?} {:02x?}", kb1, kb2);
+        kwp.key_bytes = vec![kb1, kb2];
 
         if kb2 != 0x8f {
             return Err(Error::new("Unexpected protocol identifier."));
@@ -73,14 +205,369 @@ impl Kwp2000 {
 
         // TODO: read timing parameters?
 
-        // Start diagnostic session. The 0x89 parameter might be manufacturer
-        // specific and will have to be modified.
-        kwp.write_block(0x80, physical_address, &[0x10, 0x89])?;
-        kwp.read_block()?; // TODO: check this
+        kwp.start_diagnostic_session(DIAGNOSTIC_SESSION)?;
 
         Ok(kwp)
     }
 
+    /**
+     * Start the given diagnostic session using the startDiagnosticSession
+     * service, failing if the ECU doesn't confirm it.
+     */
+    fn start_diagnostic_session(&mut self, session: u8) -> Result<(), Error> {
+        self.write_block(0x80, self.physical_address, &[0x10, session])?;
+
+        let data = self.read_block()?;
+
+        check_session_response(&data, session)
+    }
+
+    /**
+     * Set how long the session may be idle before [Diagnose::keep_alive]
+     * sends a testerPresent request. ECUs usually end the session after 2
+     * to 5 seconds without requests.
+     */
+    pub fn set_keepalive_interval(&mut self, interval: Duration) {
+        self.keepalive_interval = interval;
+    }
+
+    /**
+     * Return details about the initialization, such as the baud rate and the
+     * key bytes.
+     */
+    pub fn init_report(&self) -> InitReport {
+        InitReport {
+            key_bytes: self.key_bytes.clone(),
+            ..self.kline.init_report()
+        }
+    }
+
+    /**
+     * Read DTCs using the readDiagnosticTroubleCodesByStatus service, with the
+     * given status and group parameters. See [DTC_STATUS_DEFAULT] and
+     * [DTC_GROUP_ALL] for the values used by [Diagnose::read_dtcs].
+     */
+    pub fn read_dtcs_by_status(
+        &mut self,
+        status: u8,
+        group: u16,
+    ) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+        self.write_block(
+            0x80,
+            self.physical_address,
+            &[0x18, status, (group >> 8) as u8, (group & 0xff) as u8],
+        )?;
+
+        let data = self.read_block()?;
+
+        parse_dtcs(&data)
+    }
+
+    /**
+     * Read all stored DTCs regardless of their status. Some ECUs only report
+     * certain DTCs this way.
+     */
+    pub fn read_all_dtcs(&mut self) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+        self.read_dtcs_by_status(DTC_STATUS_ANY, DTC_GROUP_ANY)
+    }
+
+    /**
+     * Read the status of a single DTC using the readStatusOfDiagnosticTrouble
+     * Codes service. Returns None if the DTC is not stored.
+     */
+    pub fn read_dtc_status(&mut self, code: u16) -> Result<Option<DtcStatus>, Error> {
+        let data = self.request(
+            "readStatusOfDiagnosticTroubleCodes",
+            &[0x17, (code >> 8) as u8, (code & 0xff) as u8],
+        )?;
+
+        if data.is_empty() {
+            return Err(Error::new("Unexpected response to readStatusOfDiagnosticTroubleCodes command."));
+        }
+
+        // The status may be followed by manufacturer-specific environmental
+        // data, which is ignored.
+        if data[0] == 0 || data.len() < 4 {
+            return Ok(None);
+        }
+
+        if ((data[1] as u16) << 8) + data[2] as u16 != code {
+            return Err(Error::new("DTC of response did not match."));
+        }
+
+        Ok(Some(DtcStatus::from_kwp2000_status(data[3])))
+    }
+
+    /**
+     * Read the record with the given 16-bit identifier using the
+     * readDataByCommonIdentifier service, returning just the record data.
+     */
+    pub fn read_common_identifier(&mut self, rcid: u16) -> Result<Vec<u8>, Error> {
+        let data = self.request(
+            "readDataByCommonIdentifier",
+            &[0x22, (rcid >> 8) as u8, (rcid & 0xff) as u8],
+        )?;
+
+        if data.len() < 2 {
+            return Err(Error::new("Unexpected response to readDataByCommonIdentifier command."));
+        }
+
+        if ((data[0] as u16) << 8) + data[1] as u16 != rcid {
+            return Err(Error::new("Identifier of response did not match."));
+        }
+
+        Ok(data[2..].to_vec())
+    }
+
+    /**
+     * Send a request for the given service and return the positive response,
+     * without the response service identifier. Negative responses are
+     * returned as errors, unless the ECU just asks for more time.
+     */
+    fn request(&mut self, name: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.write_block(0x80, self.physical_address, data)?;
+
+        loop {
+            let response = self.read_block()?;
+
+            if response.len() >= 3 && response[0] == 0x7f {
+                if response[2] == NRC_RESPONSE_PENDING {
+                    debug!("ECU requested more time for {} command.", name);
+                    continue;
+                }
+
+                return Err(Error::new(format!(
+                    "ECU rejected {} command (0x{:02x}).",
+                    name, response[2]
+                )));
+            }
+
+            if response.is_empty() || response[0] != data[0] + 0x40 {
+                return Err(Error::new(format!("Unexpected response to {} command.", name)));
+            }
+
+            return Ok(response[1..].to_vec());
+        }
+    }
+
+    /**
+     * Request the seed for the given security access level (odd numbers)
+     * using the securityAccess service. An empty or all-zero seed means the
+     * ECU is already unlocked.
+     */
+    pub fn request_seed(&mut self, level: u8) -> Result<Vec<u8>, Error> {
+        let response = self.request("securityAccess", &[0x27, level])?;
+
+        if response.is_empty() || response[0] != level {
+            return Err(Error::new("Security access level of response did not match."));
+        }
+
+        let seed = response[1..].to_vec();
+        if seed.iter().all(|b| *b == 0) {
+            self.security_access = true;
+        }
+
+        Ok(seed)
+    }
+
+    /**
+     * Send the key computed from the seed of the given security access level,
+     * see [Kwp2000::request_seed].
+     */
+    pub fn send_key(&mut self, level: u8, key: &[u8]) -> Result<(), Error> {
+        let key_level = level
+            .checked_add(1)
+            .ok_or_else(|| Error::new("Invalid security access level."))?;
+
+        let mut data = vec![0x27, key_level];
+        data.extend(key);
+
+        self.request("securityAccess", &data)?;
+        self.security_access = true;
+
+        Ok(())
+    }
+
+    /**
+     * Perform security access for the given level, computing the key with the
+     * given algorithm.
+     */
+    pub fn security_access(&mut self, level: u8, algorithm: &dyn SeedKey) -> Result<(), Error> {
+        let seed = self.request_seed(level)?;
+        if self.security_access {
+            return Ok(());
+        }
+
+        let key = algorithm.compute(&seed, level);
+        self.send_key(level, &key)
+    }
+
+    /**
+     * Read the given memory range using the requestUpload, transferData and
+     * requestTransferExit services. Requires security access.
+     */
+    pub fn upload(&mut self, address: u32, size: u32) -> Result<Vec<u8>, Error> {
+        let max_block = self.request_transfer(0x35, "requestUpload", address, size)?;
+
+        let mut data = Vec::with_capacity(size as usize);
+        let mut counter: u8 = 1;
+        while data.len() < size as usize {
+            let response = self.request("transferData", &[0x36, counter])?;
+
+            if response.is_empty() || response[0] != counter {
+                return Err(Error::new("Block sequence counter of response did not match."));
+            }
+
+            if response.len() < 2 || response.len() - 1 > max_block {
+                return Err(Error::new("Unexpected transferData block length."));
+            }
+
+            data.extend(&response[1..]);
+            counter = counter.wrapping_add(1);
+        }
+
+        self.request("requestTransferExit", &[0x37])?;
+
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    /**
+     * Write the given data to memory using the requestDownload, transferData
+     * and requestTransferExit services. Requires security access.
+     *
+     * Writing the wrong data, or interrupting the transfer, may leave the
+     * ECU unusable.
+     */
+    pub fn download(&mut self, address: u32, data: &[u8]) -> Result<(), Error> {
+        let max_block =
+            self.request_transfer(0x34, "requestDownload", address, data.len() as u32)?;
+
+        let mut counter: u8 = 1;
+        for chunk in data.chunks(max_block) {
+            let mut block = vec![0x36, counter];
+            block.extend(chunk);
+
+            let response = self.request("transferData", &block)?;
+
+            if response.is_empty() || response[0] != counter {
+                return Err(Error::new("Block sequence counter of response did not match."));
+            }
+
+            counter = counter.wrapping_add(1);
+        }
+
+        self.request("requestTransferExit", &[0x37])?;
+
+        Ok(())
+    }
+
+    /**
+     * Request an upload or download of the given memory range, returning the
+     * maximum number of data bytes per transferData block.
+     */
+    fn request_transfer(
+        &mut self,
+        service: u8,
+        name: &str,
+        address: u32,
+        size: u32,
+    ) -> Result<usize, Error> {
+        if !self.security_access {
+            return Err(Error::new("Security access required for memory transfers."));
+        }
+
+        if address > 0xffffff || size > 0xffffff {
+            return Err(Error::new("Memory address and size are limited to 24 bits."));
+        }
+
+        // Memory address and size are 3 bytes each, with an uncompressed,
+        // unencrypted data format in between.
+        let request = [
+            service,
+            (address >> 16) as u8,
+            (address >> 8) as u8,
+            address as u8,
+            0x00,
+            (size >> 16) as u8,
+            (size >> 8) as u8,
+            size as u8,
+        ];
+
+        let response = self.request(name, &request)?;
+
+        // The maximum block length includes the service identifier and block
+        // sequence counter, and is limited by the K line block size.
+        let max_length = response.get(0).map_or(MAX_BLOCK_DATA, |l| *l as usize);
+        let max_block = max_length.min(MAX_BLOCK_DATA).saturating_sub(2);
+        if max_block == 0 {
+            return Err(Error::new("Invalid maximum block length."));
+        }
+
+        Ok(max_block)
+    }
+
+    /**
+     * Request the ECU to periodically push the given identifiers at the given
+     * rate using readDataByPeriodicIdentifier, until stopped with
+     * [Kwp2000::stop_periodic]. The data is read using
+     * [Kwp2000::read_periodic].
+     */
+    pub fn start_periodic(&mut self, rate: TransmissionRate, ids: &[u8]) -> Result<(), Error> {
+        if ids.is_empty() || ids.len() > MAX_BLOCK_DATA - 2 {
+            return Err(Error::new("Invalid number of periodic identifiers."));
+        }
+
+        let mut request = vec![0x2a, rate.mode()];
+        request.extend(ids);
+
+        self.request("readDataByPeriodicIdentifier", &request)?;
+        self.periodic = true;
+
+        Ok(())
+    }
+
+    /**
+     * Read the next message pushed by the ECU after
+     * [Kwp2000::start_periodic], returning the identifier and its data.
+     */
+    pub fn read_periodic(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let data = self.read_block()?;
+
+        if data.len() < 2 || data[0] != 0x6a {
+            return Err(Error::new("Unexpected periodic data message."));
+        }
+
+        Ok((data[1], data[2..].to_vec()))
+    }
+
+    /**
+     * Stop the periodic transmission started by [Kwp2000::start_periodic].
+     * Messages still being pushed by the ECU are discarded.
+     */
+    pub fn stop_periodic(&mut self) -> Result<(), Error> {
+        self.periodic = false;
+        self.write_block(0x80, self.physical_address, &[0x2a, 0x04])?;
+
+        // The response may be preceded by data that was already on its way.
+        for _ in 0..MAX_STOP_BLOCKS {
+            let data = self.read_block()?;
+
+            if data.len() >= 3 && data[0] == 0x7f {
+                return Err(Error::new(format!(
+                    "ECU rejected readDataByPeriodicIdentifier command (0x{:02x}).",
+                    data[2]
+                )));
+            }
+
+            if data == [0x6a] {
+                return Ok(());
+            }
+        }
+
+        Err(Error::new("ECU did not stop periodic transmission."))
+    }
+
     /**
      * Write a data block to the K line via KWP2000.
      *
@@ -89,7 +576,7 @@ impl Kwp2000 {
      * requests. Source address is always 0xf1 as per ISO 15031-5.
      */
     fn write_block(&mut self, format: u8, target: u8, data: &[u8]) -> Result<(), Error> {
-        busy_wait(SystemTime::now(), self.block_delay);
+        busy_wait(Instant::now(), self.block_delay);
 
         let mut msg: Vec<u8> = vec![format + data.len() as u8, target, 0xf1];
         msg.extend(data);
@@ -97,7 +584,9 @@ impl Kwp2000 {
         let crc: Wrapping<u8> = msg.iter().map(|x| Wrapping(*x)).sum();
         msg.push(crc.0);
 
-        debug!("SEND {:02x?}", &msg);
+        trace!(target: FRAME_LOG_TARGET, "SEND {:02x?}", &msg);
+        self.request_sent = Some(Instant::now());
+        self.last_request = Instant::now();
 
         for byte in msg {
             self.kline.write_byte(byte, false)?;
@@ -109,53 +598,71 @@ impl Kwp2000 {
     /**
      * Read a data block from the K line via KWP2000, returning just the data
      * bytes, without format and source/target addresses.
+     *
+     * The bytes of a response are at most P1 apart, so a longer gap ends the
+     * message, even if it is shorter than indicated by the header.
      */
     fn read_block(&mut self) -> Result<Vec<u8>, Error> {
         let header = self.kline.read_byte(false)?;
-
-        let mut crc: Wrapping<u8> = Wrapping(header);
         let length = header as usize & 0x3f;
 
         let mut msg = Vec::with_capacity(length + 4);
         msg.push(header);
 
-        for i in 0..length + 3 {
-            let byte = self.kline.read_byte(false)?;
-            msg.push(byte);
-
-            if i < length + 2 {
-                crc += Wrapping(byte);
+        let timeout = Duration::from_millis(self.inter_byte_timeout);
+        while msg.len() < length + 4 {
+            match self.kline.read_byte_within(timeout)? {
+                Some(byte) => msg.push(byte),
+                None => {
+                    warn!("Response shorter than indicated by header.");
+                    break;
+                }
             }
         }
 
-        debug!("RECV {:02x?}", &msg);
+        trace!(target: FRAME_LOG_TARGET, "RECV {:02x?}", &msg);
+
+        if let Some(sent) = self.request_sent.take() {
+            record_latency(sent.elapsed());
+        }
+
+        if msg.len() < 4 {
+            return Err(Error::connection_lost("Incomplete response."));
+        }
 
+        let crc: Wrapping<u8> = msg[..msg.len() - 1].iter().map(|x| Wrapping(*x)).sum();
         if crc.0 != msg[msg.len() - 1] {
-            return Err(Error::new("CRC Error"));
+            return Err(Error::new(format!(
+                "CRC Error: computed 0x{:02x}, received 0x{:02x} in {:02x?}",
+                crc.0,
+                msg[msg.len() - 1],
+                msg
+            )));
         }
 
-        Ok(msg[3..(length + 3)].into())
+        Ok(msg[3..(msg.len() - 1)].into())
     }
 }
 
 impl Diagnose for Kwp2000 {
-    fn read_dtcs(&mut self, _pending: bool) -> Result<Vec<DiagnosticTroubleCode>, Error> {
-        self.write_block(0x80, self.physical_address, &[0x18, 0x02, 0xff, 0x00])?;
-
-        let data = self.read_block()?;
-
-        if data[0] != 0x58 {
-            return Err(Error::new("Unexpected response to readDiagnosticTroubleCodesByStatus command."));
+    fn read_dtcs(&mut self, category: DtcCategory) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+        if category == DtcCategory::Permanent {
+            return Err(Error::new("KWP2000 doesn't support permanent DTCs."));
         }
 
-        let mut dtcs = Vec::with_capacity(data[1] as usize);
-        for chunk in data[2..].chunks(3) {
-            let code = ((chunk[0] as u16) << 8) + (chunk[1] as u16);
-            let status = chunk[2];
-            dtcs.push(DiagnosticTroubleCode::Oem(code, status));
+        let dtcs = self.read_dtcs_by_status(DTC_STATUS_DEFAULT, DTC_GROUP_ALL)?;
+
+        if category == DtcCategory::Stored {
+            return Ok(dtcs);
         }
 
-        return Ok(dtcs);
+        // There is no separate request for pending DTCs, instead the storage
+        // state in the DTC status byte indicates whether the DTC is still
+        // maturing.
+        Ok(dtcs
+            .into_iter()
+            .filter(|dtc| dtc.status().map_or(false, |status| status.pending))
+            .collect())
     }
 
     fn clear_dtcs(&mut self) -> Result<(), Error> {
@@ -182,6 +689,77 @@ impl Diagnose for Kwp2000 {
     }
 
     fn read_data(&mut self, _pid: u8, _freeze_frame: bool) -> Result<DiagnosticData, Error> {
-        todo!();
+        Err(Error::new("Reading data is not implemented for KWP2000."))
+    }
+
+    fn keep_alive(&mut self) -> Result<(), Error> {
+        // Any other request keeps the session alive as well.
+        if self.last_request.elapsed() < self.keepalive_interval {
+            return Ok(());
+        }
+
+        // While the ECU is pushing periodic data, a response would get mixed
+        // up with it, so request none. The ECU may already be sending the
+        // next block, which would be mistaken for the echo of the request, so
+        // wait for a gap between blocks first, dropping the partial block.
+        if self.periodic {
+            let gap = Duration::from_millis(self.inter_byte_timeout);
+            while let Some(byte) = self.kline.read_byte_within(gap)? {
+                debug!("Dropping periodic data byte 0x{:02x}.", byte);
+            }
+
+            return self.write_block(0x80, self.physical_address, &[0x3e, 0x02]);
+        }
+
+        self.request("testerPresent", &[0x3e, 0x01])?;
+
+        Ok(())
+    }
+
+    fn ecu_reset(&mut self) -> Result<(), Error> {
+        self.request("ecuReset", &[0x11, 0x01])?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dtcs() {
+        assert_eq!(
+            parse_dtcs(&[0x58, 0x02, 0x46, 0x5b, 0x62, 0x04, 0x69, 0x24]).unwrap(),
+            vec![
+                DiagnosticTroubleCode::Kwp2000(0x465b, 0x62),
+                DiagnosticTroubleCode::Kwp2000(0x0469, 0x24),
+            ]
+        );
+    }
+
+    #[test]
+    fn odd_length_dtcs() {
+        // The incomplete trailing DTC is ignored.
+        assert_eq!(
+            parse_dtcs(&[0x58, 0x02, 0x46, 0x5b, 0x62, 0x04]).unwrap(),
+            vec![DiagnosticTroubleCode::Kwp2000(0x465b, 0x62)]
+        );
+        assert!(parse_dtcs(&[0x58, 0x00]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn session_response() {
+        assert!(check_session_response(&[0x50, 0x89], 0x89).is_ok());
+        assert!(check_session_response(&[0x50, 0x81], 0x89).is_err());
+        assert!(check_session_response(&[0x50], 0x89).is_err());
+        assert!(check_session_response(&[0x7f, 0x10, 0x12], 0x89).is_err());
+        assert!(check_session_response(&[], 0x89).is_err());
+    }
+
+    #[test]
+    fn short_dtc_response() {
+        assert!(parse_dtcs(&[0x58]).is_err());
+        assert!(parse_dtcs(&[]).is_err());
     }
 }
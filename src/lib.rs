@@ -9,14 +9,17 @@
  *
  * Diagnosis functionality common to all protocols is implemented using the
  * [dtc::Diagnose] trait, while functionality common to all OBD2 protocols is
- * implemented using [obd2::Obd2Protocol].
+ * implemented using [obd2::Obd2Protocol]. Heavy-duty vehicles using SAE J1939
+ * are supported separately by [j1939::J1939].
  */
 
 pub mod can;
 pub mod diagnose;
 pub mod error;
+pub mod j1939;
 pub mod kline;
 pub mod kwp1281;
 pub mod kwp2000;
 pub mod misc;
 pub mod obd2;
+pub mod seedkey;
@@ -1,45 +1,88 @@
-use std::fs::File;
-use std::io::{stdout, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{stderr, stdout, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use colored::*;
 use docopt::Docopt;
 use env_logger;
-use log::{error, info, Level, LevelFilter};
+use log::{debug, error, info, warn, Level, LevelFilter};
 use serde::{de, Deserialize, Deserializer};
+use serde_json::json;
 
 mod can;
 mod diagnose;
 mod error;
+mod j1939;
 mod kline;
 mod kwp1281;
 mod kwp2000;
 mod misc;
 mod obd2;
+mod seedkey;
 
 use crate::can::*;
 use crate::diagnose::*;
 use crate::error::*;
+use crate::j1939::*;
 use crate::kline::*;
 use crate::kwp1281::*;
 use crate::kwp2000::*;
 use crate::misc::*;
 use crate::obd2::*;
+use crate::seedkey::*;
 
 const VERSION: &'static str = "v0.1";
+
+/// Default number of retries for the K line initialization
+const DEFAULT_INIT_RETRIES: u32 = 3;
+
+/// Delay between K line initialization attempts
+const INIT_RETRY_DELAY_MILLIS: u64 = 1000;
+
+/// Maximum number of attempts to reinitialize a lost connection
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+
+/// Name of the config file for option defaults
+const CONFIG_FILE: &'static str = "rustbucket.toml";
+
+/// Number of rotated CSV files kept when using --log-rotate
+const LOG_ROTATE_KEEP: u32 = 5;
+
+/// Default time to wait for the ECU to come back after a reset
+const DEFAULT_SETTLE_MILLIS: u64 = 2000;
+
+/// Default security access level for memory transfers
+const DEFAULT_SECURITY_LEVEL: u8 = 0x01;
+
+/// Default address to listen on for the serve command
+const DEFAULT_LISTEN_ADDRESS: &'static str = "127.0.0.1:8080";
+
+/// Whether the user agreed to communicate with the airbag controller, so
+/// reconnecting doesn't ask again
+static AIRBAG_CONFIRMED: AtomicBool = AtomicBool::new(false);
+
 const USAGE: &'static str = "
 Usage:
-    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] read-dtcs [-v] [--bitrate=<bps>] [--pending]
-    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] clear-dtcs [-v] [--bitrate=<bps>]
-    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] read-data <pid> [-v] [-t [--log=<logfile>]] [--freeze-frame]
-    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] dump-data [-v] [-r] [--freeze-frame]
-    rustbucket kwp1281 [--ecu=<ecu>] adaptation <pid> [<value>] [-v] [--test] [--bitrate=<bps>]
-    rustbucket kwp1281 [--ecu=<ecu>] basic-setting <pid> [-v] [--bitrate=<bps>]
-    rustbucket <protocol> simulator [-v] [--bitrate=<bps>]
-    rustbucket test-hardware (tx|rx) [-v] [--bitrate=<bps>]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] read-dtcs [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>] [--stored] [--pending] [--permanent] [--all] [--previous] [--status]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] clear-dtcs [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] read-data (<pid> | --pids=<list> | --pid-file=<path>) [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--timing] [--show-raw] [-t [--log=<logfile> [--timestamp=<format>] [--append] [--log-rotate=<bytes>]] [--reconnect] | --periodic [--rate=<rate>] [--keepalive-interval=<ms>]] [--freeze-frame]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] dump-data [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--timing] [-r | --show-raw | --json] [--freeze-frame] [--reconnect]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] readiness [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>] [--allow-incomplete=<n>]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] ecu-reset [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>] [--resume [--settle=<ms>]]
+    rustbucket <protocol> [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] serve [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>] [--listen=<addr>]
+    rustbucket kwp2000 [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] dtc-status <code> [-v] [--trace-frames] [--log-json] [--bitrate=<bps>]
+    rustbucket kwp2000 [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] read-identifier <id> [-v] [--trace-frames] [--log-json] [--bitrate=<bps>]
+    rustbucket kwp2000 [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] upload <address> <size> <file> [-v] [--trace-frames] [--log-json] [--bitrate=<bps>] [--security-level=<n>] [--seed-key=<name>]
+    rustbucket kwp2000 [--ecu=<ecu>] [--phys=<addr>] [--init-retries=<n>] [--write-delay=<us>] download <address> <file> [-v] [--trace-frames] [--log-json] [--bitrate=<bps>] [--security-level=<n>] [--seed-key=<name>]
+    rustbucket kwp1281 [--ecu=<ecu>] [--init-retries=<n>] [--write-delay=<us>] adaptation <pid> [<value>] [-v] [--trace-frames] [--log-json] [--test] [--bitrate=<bps>]
+    rustbucket kwp1281 [--ecu=<ecu>] [--init-retries=<n>] [--write-delay=<us>] measuring-block <pid> [-v] [--trace-frames] [--log-json] [--show-raw] [--bitrate=<bps>]
+    rustbucket kwp1281 [--ecu=<ecu>] [--init-retries=<n>] [--write-delay=<us>] basic-setting <pid> [-v] [--trace-frames] [--log-json] [--bitrate=<bps>]
+    rustbucket <protocol> simulator [-v] [--trace-frames] [--log-json] [--sudo-cmd=<prog>] [--bitrate=<bps>]
+    rustbucket test-hardware (tx|rx) [-v] [--trace-frames] [--log-json] [--bitrate=<bps>]
     rustbucket (-h | --help)
     rustbucket --version
 
@@ -49,6 +92,8 @@ Args:
                             - kwp1281   KWP1281, K line only
                             - iso9141   ISO 9141, K & L line, unimplemented
                             - kwp2000   KWP2000 / ISO 14230, K & L line
+                            - j1939     SAE J1939 for heavy-duty vehicles,
+                                        read-dtcs only
 
 Commands:
     read-dtcs           Read Diagnostic Trouble Codes.
@@ -58,9 +103,33 @@ Commands:
     dump-data           Enumerate through all data PIDs/groups, and dump it all
                             either formatted or in hex.
                             Freeze frame not supported on KWP1281.
+    readiness           Check whether the vehicle is ready for an emissions
+                            inspection, based on the readiness monitors, the
+                            MIL and the number of DTCs. Not supported on
+                            KWP1281 and KWP2000.
+    ecu-reset           Reset the ECU. Not supported on KWP1281, and only by
+                            CAN ECUs supporting UDS. On CAN, the reset is
+                            only sent to the ECU answering OBD queries.
+    serve               Serve current data as JSON via HTTP, e.g. for a
+                            dashboard. GET /pid/<pid> returns the decoded
+                            value of a PID/group, GET /dtcs the stored DTCs.
+                            Not supported on KWP2000.
+    dtc-status          Read the detailed status of a single DTC.
+    read-identifier     Read the record with the given 16-bit common
+                            identifier and dump it in hex.
+    upload              Read <size> bytes of ECU memory starting at <address>
+                            and write them to <file>. Requires security
+                            access, the key for the seed sent by the ECU is
+                            prompted for unless --seed-key is given.
+    download            Write the contents of <file> to ECU memory starting
+                            at <address>. Requires security access like
+                            upload. Writing the wrong data or interrupting
+                            the transfer may leave the ECU unusable.
     adaptation          Read and optionally modify the adaptation values.
                             If no new value is given, adaptation value is only
                             read. If new value is given, the value is modified.
+    measuring-block     Read a measuring block (group) and print each value
+                            with the formula used for decoding it.
     basic-setting       Perform a basic setting for the given group. Keeps
                             watching data group after basic setting command.
     simulator           Run a car simulater for testing.
@@ -72,26 +141,129 @@ Options:
     -h --help           Show usage information.
     --version           Show version.
     -v --verbose        Show more output.
+    --trace-frames      Log all frames sent to and received from the vehicle,
+                            independently of --verbose.
+    --log-json          Write log messages as JSON objects, one per line,
+                            e.g. for shipping them to a log aggregator.
     --ecu=<ecu>         ECU to initialize protocol with. Defaults to 0x01
                             (engine control unit). Only for K line protocols.
                             Proceed with caution for other units, especially
                             airbag controllers.
     --phys=<addr>       Physical address to use for KWP2000 protocol. This is
                             manufacturer specific. Good luck.
+    --init-retries=<n>  Number of times to retry the K line initialization if
+                            it fails, e.g. because the sync byte was missed.
+                            Defaults to 3.
+    --write-delay=<us>  Delay before writing each byte to the K line in
+                            microseconds. Lowering it speeds up communication
+                            with fast ECUs, but sluggish ones might not
+                            respond anymore. Defaults to 5000.
+    --sudo-cmd=<prog>   Program used to run commands as root when setting up
+                            the CAN interface, e.g. sudo, doas or pkexec.
+                            By default, the first one installed is used.
     --bitrate=<bps>     Set baud/bit rate manually. For K line protocols this
                             will be determined automagically by default.
-                            For the CAN bus, this defaults to 500,000.
-    --pending           Read pending DTCs instead of stored ones.
+                            For the CAN bus, this defaults to 500,000, or
+                            250,000 for J1939.
+    --stored            Read stored DTCs. This is the default if no other
+                            category is given.
+    --pending           Read pending DTCs. (not supported by KWP1281, for
+                            KWP2000 this filters DTCs by their status byte)
+    --permanent         Read permanent DTCs. (CAN only)
+    --all               Read all stored DTCs regardless of their status.
+                            (KWP2000 only)
+    --previous          Read previously active DTCs (DM2) instead of the
+                            active ones (DM1). (J1939 only)
+    --status            Print the decoded status flags of each DTC.
                             (not supported by KWP1281)
+    --allow-incomplete=<n>
+                        Number of incomplete monitors allowed for passing the
+                            readiness check. Many inspection programs allow 1
+                            for model year 2001 and newer, and 2 for older
+                            vehicles. Defaults to 0.
+    --security-level=<n>
+                        Security access level to request the seed for.
+                            Levels are odd numbers, the key is sent with the
+                            level + 1. Defaults to 0x01.
+    --seed-key=<name>   Compute the security access key with a built-in
+                            algorithm instead of prompting for it. One of:
+                            - vag-add         Seed + 0x11170, level 1 of
+                                              many VAG KWP2000 ECUs
+                            - vag-rotate      Shift/XOR scheme of some
+                                              Bosch ME7 ECUs
+                            - constant:<hex>  Always send the given key
+    --keepalive-interval=<ms>
+                        Idle time after which a testerPresent request is sent
+                            to keep the session alive while reading periodic
+                            data. (KWP2000 only) Defaults to 2000.
+    --resume            Wait for the ECU to come back after the reset, and
+                            check that it responds again. For K line
+                            protocols, this checks that the initialization
+                            succeeds, the new session is closed again.
+    --settle=<ms>       Time to wait after the reset before resuming.
+                            Defaults to 2000.
+    --listen=<addr>     Address and port to serve data on. Use 0.0.0.0 to
+                            serve on all interfaces, but keep in mind that
+                            anyone on the network can then talk to the
+                            vehicle. Defaults to 127.0.0.1:8080.
     -t --tail           Keep requerying data.
-    -l --log=<logfile>  Write floating point values to CSV file.
+    --pids=<list>       Read multiple PIDs/groups in sequence, given as a
+                            comma-separated list, e.g. 1,2,0x0c. The values of
+                            KWP1281 groups are labeled by their field index.
+    --pid-file=<path>   Read all PIDs/groups listed in a file, one per line,
+                            as hex (0x0c) or decimal. Anything following a
+                            # is a comment, which is used as the column name
+                            in the CSV file.
+    -l --log=<logfile>  Write floating point values to CSV file. Use - to
+                            write them to stdout instead, in which case all
+                            other output goes to stderr, without colors.
+    --append            Append to the CSV file instead of overwriting it.
+                            The header is only written to empty files.
+    --log-rotate=<bytes>
+                        Once the CSV file exceeds the given size, rename it
+                            to <logfile>.1 (<logfile>.1 to <logfile>.2 and so
+                            on) and start a new one. At most 5 rotated files
+                            are kept.
+    --timestamp=<format>
+                        Format of the time column in the CSV file. One of:
+                            - relative  Seconds since the start
+                            - rfc3339   UTC wall clock time, e.g.
+                                        2024-01-02T03:04:05.678Z
+                            Defaults to relative.
+    --reconnect         Reinitialize the protocol if the connection is lost
+                            while reading data, e.g. the K line session ended
+                            or the CAN controller went bus-off.
+    --periodic          Request the ECU to push the PIDs at a fixed rate
+                            instead of polling them, which allows for much
+                            higher sample rates. Implies --tail, and prints
+                            the data in hex. (KWP2000 only)
+    --rate=<rate>       Rate of periodic transmission. One of slow, medium
+                            or fast. Defaults to fast.
+    --timing            Print the minimum, average and maximum query round
+                            trip times on exit.
     --freeze-frame      Query data from freeze frame.
     -r --raw            Dump data in raw hex.
+    --show-raw          Show the raw hex bytes after the decoded values.
+    --json              Dump data as a JSON object, including the reasons
+                            for PIDs that failed.
     --test              Write adaptation value in test mode.
 
-With the exception of the bitrate, all numerical arguments can be given both in
-    decimal and hex if prefixed with '0x'. Hex values should be zero-padded to
-    an even length.
+All numerical arguments can be given both in decimal and hex if prefixed with
+    '0x'.
+
+Defaults for --sudo-cmd, --init-retries and --write-delay can be set in a
+    rustbucket.toml file in the current directory or in ~/.config/rustbucket/,
+    e.g. sudo-cmd = \"doas\". Options given on the command line take
+    precedence. The default --bitrate is set separately via can-bitrate for
+    CAN and kline-baud for the K line protocols.
+    The config file can also set the devices below via serial-device,
+    can-interface and gpio-chip.
+
+Environment:
+    RUSTBUCKET_SERIAL_DEVICE    UART device for the K line (/dev/ttyO1)
+    RUSTBUCKET_CAN_INTERFACE    CAN network interface (can0)
+    RUSTBUCKET_GPIO_CHIP        GPIO chip of the K line pins (/dev/gpiochip0)
+    These take precedence over the config file.
 
 For more information on OBD2 PIDs, consult resources such as:
     https://en.wikipedia.org/wiki/OBD-II_PIDs#Service_01
@@ -112,11 +284,11 @@ impl<'de> Deserialize<'de> for HexInput8 {
         let s = String::deserialize(deserializer)?;
 
         let value = if s.len() >= 2 && &s[0..2] == "0x" {
-            if s.len() != 4 {
+            if s.len() < 3 || s.len() > 4 {
                 return Err(de::Error::custom("Unexpected hex input length."));
             }
 
-            u8::from_str_radix(&s[2..4], 16).map_err(de::Error::custom)?
+            u8::from_str_radix(&s[2..], 16).map_err(de::Error::custom)?
         } else {
             u8::from_str_radix(&s, 10).map_err(de::Error::custom)?
         };
@@ -146,12 +318,11 @@ impl<'de> Deserialize<'de> for HexInput16 {
         let s = String::deserialize(deserializer)?;
 
         let value = if s.len() >= 2 && &s[0..2] == "0x" {
-            if s.len() != 4 && s.len() != 6 {
+            if s.len() < 3 || s.len() > 6 {
                 return Err(de::Error::custom("Unexpected hex input length."));
             }
 
-            u16::from_str_radix(&s[2..4], 16).map_err(de::Error::custom)?
-                << 8 + u16::from_str_radix(&s[4..6], 16).map_err(de::Error::custom)?
+            u16::from_str_radix(&s[2..], 16).map_err(de::Error::custom)?
         } else {
             u16::from_str_radix(&s, 10).map_err(de::Error::custom)?
         };
@@ -174,6 +345,40 @@ impl Into<[u8; 2]> for HexInput16 {
     }
 }
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct HexInput64 {
+    value: u64,
+}
+
+impl<'de> Deserialize<'de> for HexInput64 {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        let value = if s.len() >= 2 && &s[0..2] == "0x" {
+            if s.len() < 3 || s.len() > 18 {
+                return Err(de::Error::custom("Unexpected hex input length."));
+            }
+
+            u64::from_str_radix(&s[2..], 16).map_err(de::Error::custom)?
+        } else {
+            u64::from_str_radix(&s, 10).map_err(de::Error::custom)?
+        };
+
+        Ok(Self { value })
+    }
+}
+
+impl Deref for HexInput64 {
+    type Target = u64;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
 /// Enum of protocols for CLI arg parsing
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 enum Protocol {
@@ -181,6 +386,22 @@ enum Protocol {
     Kwp1281,
     Iso9141,
     Kwp2000,
+    J1939,
+}
+
+/// Enum of CSV timestamp formats for CLI arg parsing
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+enum Timestamp {
+    Relative,
+    Rfc3339,
+}
+
+/// Enum of periodic transmission rates for CLI arg parsing
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+enum Rate {
+    Slow,
+    Medium,
+    Fast,
 }
 
 /// CLI args
@@ -190,7 +411,15 @@ struct Args {
     cmd_clear_dtcs: bool,
     cmd_read_data: bool,
     cmd_dump_data: bool,
+    cmd_readiness: bool,
+    cmd_serve: bool,
+    cmd_ecu_reset: bool,
+    cmd_dtc_status: bool,
+    cmd_read_identifier: bool,
+    cmd_upload: bool,
+    cmd_download: bool,
     cmd_adaptation: bool,
+    cmd_measuring_block: bool,
     cmd_basic_setting: bool,
     cmd_simulator: bool,
     cmd_test_hardware: bool,
@@ -199,38 +428,191 @@ struct Args {
     arg_protocol: Option<Protocol>,
     arg_pid: Option<HexInput8>,
     arg_value: Option<HexInput16>,
+    arg_code: Option<HexInput16>,
+    arg_id: Option<HexInput16>,
+    arg_address: Option<HexInput64>,
+    arg_size: Option<HexInput64>,
+    arg_file: Option<String>,
     flag_verbose: bool,
+    flag_trace_frames: bool,
+    flag_log_json: bool,
     flag_ecu: Option<HexInput8>,
     flag_phys: Option<HexInput8>,
-    flag_bitrate: Option<u64>,
+    flag_init_retries: Option<u32>,
+    flag_write_delay: Option<u64>,
+    flag_bitrate: Option<HexInput64>,
+    flag_sudo_cmd: Option<String>,
+    flag_timing: bool,
+    flag_show_raw: bool,
+    flag_json: bool,
+    flag_stored: bool,
     flag_pending: bool,
+    flag_permanent: bool,
+    flag_all: bool,
+    flag_previous: bool,
+    flag_status: bool,
+    flag_allow_incomplete: Option<usize>,
+    flag_listen: Option<String>,
+    flag_keepalive_interval: Option<u64>,
+    flag_security_level: Option<HexInput8>,
+    flag_seed_key: Option<String>,
+    flag_resume: bool,
+    flag_settle: Option<u64>,
     flag_freeze_frame: bool,
     flag_tail: bool,
     flag_log: Option<String>,
+    flag_pids: Option<String>,
+    flag_pid_file: Option<String>,
+    flag_timestamp: Option<Timestamp>,
+    flag_append: bool,
+    flag_log_rotate: Option<u64>,
+    flag_reconnect: bool,
+    flag_periodic: bool,
+    flag_rate: Option<Rate>,
     flag_raw: bool,
     flag_test: bool,
 }
 
+/// Defaults for CLI options, read from the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Config {
+    sudo_cmd: Option<String>,
+    can_bitrate: Option<u64>,
+    kline_baud: Option<u64>,
+    init_retries: Option<u32>,
+    write_delay: Option<u64>,
+    serial_device: Option<String>,
+    can_interface: Option<String>,
+    gpio_chip: Option<String>,
+}
+
+impl Config {
+    /**
+     * Read the config file from the current directory, or from the user's
+     * config directory. Returns the empty default config if there is none.
+     */
+    fn load() -> Result<Self, Error> {
+        let mut paths = vec![PathBuf::from(CONFIG_FILE)];
+
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(dir) = config_dir {
+            paths.push(dir.join("rustbucket").join(CONFIG_FILE));
+        }
+
+        let path = match paths.iter().find(|p| p.is_file()) {
+            Some(p) => p,
+            None => {
+                return Ok(Self::default());
+            }
+        };
+
+        debug!("Reading config file {}", path.display());
+
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| Error::new(format!("Invalid config file {}: {}", path.display(), e)))
+    }
+
+    /**
+     * Use the config values for all options not given on the command line,
+     * and for the devices not set via environment variables.
+     */
+    fn apply(self, args: &mut Args) {
+        let env = |name: &str| std::env::var(name).ok();
+
+        let mut pins = PinMap::default();
+        if let Some(device) = env("RUSTBUCKET_SERIAL_DEVICE").or(self.serial_device) {
+            pins.serial_device = device;
+        }
+        if let Some(chip) = env("RUSTBUCKET_GPIO_CHIP").or(self.gpio_chip) {
+            pins.gpio_chip = chip;
+        }
+        set_default_pins(pins);
+
+        if let Some(interface) = env("RUSTBUCKET_CAN_INTERFACE").or(self.can_interface) {
+            set_interface(&interface);
+        }
+
+        if args.flag_sudo_cmd.is_none() {
+            args.flag_sudo_cmd = self.sudo_cmd;
+        }
+
+        // A CAN bit rate would be a wrong baud rate for the K line, and vice
+        // versa. J1939 buses use a different bit rate than OBD2 ones as well.
+        let bitrate = match args.arg_protocol {
+            Some(Protocol::Can) => self.can_bitrate,
+            Some(Protocol::Kwp1281) | Some(Protocol::Iso9141) | Some(Protocol::Kwp2000) => {
+                self.kline_baud
+            }
+            _ => None,
+        };
+        if args.flag_bitrate.is_none() {
+            args.flag_bitrate = bitrate.map(|value| HexInput64 { value });
+        }
+
+        if args.flag_init_retries.is_none() {
+            args.flag_init_retries = self.init_retries;
+        }
+
+        if args.flag_write_delay.is_none() {
+            args.flag_write_delay = self.write_delay;
+        }
+    }
+}
+
 fn init_kwp1281(args: &Args) -> Result<Kwp1281, Error> {
     let address = args.flag_ecu.clone().map(|x| *x).unwrap_or(0x01);
 
-    if address == 0x15 {
+    if address == 0x15 && !AIRBAG_CONFIRMED.load(Ordering::SeqCst) {
         if !confirm(format!("{}: Attempting to communicate with the airbag controller via KWP1281 may result in bricked hardware or deployed airbags. Are you sure you wish to proceed?", "CAUTION".bold().red()))? {
             return Err(Error::new("Aborting."));
         }
 
         println!("Proceeding. No refunds!");
+        AIRBAG_CONFIRMED.store(true, Ordering::SeqCst);
+    }
+
+    let kwp = retry(
+        1 + args.flag_init_retries.unwrap_or(DEFAULT_INIT_RETRIES),
+        Duration::from_millis(INIT_RETRY_DELAY_MILLIS),
+        || Kwp1281::init(address, args.flag_bitrate.clone().map(|x| *x)),
+    )?;
+    let report = kwp.init_report();
+    debug!("Init report: {:?}", report);
+    info!("ECU data: {:?}", String::from_utf8_lossy(&report.ecu_identification));
+    Ok(kwp)
+}
+
+fn init_kwp2000(args: &Args) -> Result<Kwp2000, Error> {
+    let address = args.flag_ecu.clone().map(|x| *x).unwrap_or(0x01);
+    let mut kwp = retry(
+        1 + args.flag_init_retries.unwrap_or(DEFAULT_INIT_RETRIES),
+        Duration::from_millis(INIT_RETRY_DELAY_MILLIS),
+        || {
+            Kwp2000::init(
+                address,
+                args.flag_bitrate.clone().map(|x| *x),
+                args.flag_phys.clone().map(|x| *x),
+            )
+        },
+    )?;
+    debug!("Init report: {:?}", kwp.init_report());
+    // TODO: read identification?
+
+    if let Some(interval) = args.flag_keepalive_interval {
+        kwp.set_keepalive_interval(Duration::from_millis(interval));
     }
 
-    let kwp = Kwp1281::init(address, args.flag_bitrate)?;
-    info!("ECU data: {:?}", String::from_utf8_lossy(&kwp.ecu_data));
     Ok(kwp)
 }
 
 fn init_protocol(args: &Args) -> Result<Box<dyn Diagnose>, Error> {
     Ok(match args.arg_protocol {
         Some(Protocol::Can) => {
-            let mut can = CanBus::init(args.flag_bitrate)?;
+            let mut can = CanBus::init(args.flag_bitrate.clone().map(|x| *x))?;
             if let Ok(vin) = can.obd_query(0x09, &[0x02]) {
                 info!("VIN: {:?}", String::from_utf8_lossy(&vin[1..]));
             } else {
@@ -241,23 +623,174 @@ fn init_protocol(args: &Args) -> Result<Box<dyn Diagnose>, Error> {
             Box::new(can)
         }
         Some(Protocol::Kwp1281) => Box::new(init_kwp1281(args)?),
-        Some(Protocol::Kwp2000) => {
-            let address = args.flag_ecu.clone().map(|x| *x).unwrap_or(0x01);
-            let kwp = Kwp2000::init(address, args.flag_bitrate, args.flag_phys.clone().map(|x| *x))?;
-            // TODO: read identification?
-            Box::new(kwp)
-        }
+        Some(Protocol::Kwp2000) => Box::new(init_kwp2000(args)?),
         _ => unimplemented!(),
     })
 }
 
+/**
+ * Shut down the given protocol after it failed with the given error, and try
+ * to initialize it again, if reconnecting is enabled.
+ */
+fn reconnect(
+    args: &Args,
+    protocol: Box<dyn Diagnose>,
+    error: Error,
+) -> Result<Box<dyn Diagnose>, Error> {
+    // Ctrl-C also makes queries fail, that's no reason to reconnect.
+    if !args.flag_reconnect || !running_flag().load(Ordering::SeqCst) {
+        return Err(error);
+    }
+
+    reinit_protocol(args, protocol, error)
+}
+
+/**
+ * Shut down the given protocol after it failed with the given error, and try
+ * to initialize it again.
+ */
+fn reinit_protocol(
+    args: &Args,
+    protocol: Box<dyn Diagnose>,
+    error: Error,
+) -> Result<Box<dyn Diagnose>, Error> {
+    // The old connection needs to be shut down first, e.g. to bring down the
+    // CAN interface before bringing it up again.
+    drop(protocol);
+
+    let mut error = error;
+    for attempt in 1..=MAX_RECONNECT_ATTEMPTS {
+        warn!(
+            "Connection lost ({}), reconnecting ({}/{})...",
+            error, attempt, MAX_RECONNECT_ATTEMPTS
+        );
+
+        match init_protocol(args) {
+            Ok(p) => {
+                info!("Reconnected.");
+                return Ok(p);
+            }
+            Err(e) => {
+                error = e;
+            }
+        }
+    }
+
+    Err(error)
+}
+
 fn cmd_read_dtcs(args: Args) -> Result<(), Error> {
-    if args.arg_protocol == Some(Protocol::Kwp1281) && args.flag_pending {
-        return Err(Error::new("KWP1281 doesn't support pending DTCs."));
+    if args.arg_protocol == Some(Protocol::J1939) {
+        return cmd_read_j1939_dtcs(args);
     }
 
-    let mut protocol = init_protocol(&args)?;
-    let dtcs = protocol.read_dtcs(args.flag_pending)?;
+    if args.flag_previous {
+        return Err(Error::new(
+            "Reading previously active DTCs is only supported by J1939.",
+        ));
+    }
+
+    let mut categories = Vec::new();
+    if args.flag_stored {
+        categories.push(DtcCategory::Stored);
+    }
+    if args.flag_pending {
+        categories.push(DtcCategory::Pending);
+    }
+    if args.flag_permanent {
+        categories.push(DtcCategory::Permanent);
+    }
+
+    if args.flag_all && !categories.is_empty() {
+        return Err(Error::new("--all can't be combined with DTC categories."));
+    }
+
+    if categories.is_empty() {
+        categories.push(DtcCategory::Stored);
+    }
+
+    if args.arg_protocol == Some(Protocol::Kwp1281) && categories != [DtcCategory::Stored] {
+        return Err(Error::new("KWP1281 only supports stored DTCs."));
+    }
+
+    if args.flag_all && args.arg_protocol != Some(Protocol::Kwp2000) {
+        return Err(Error::new("Reading all DTCs is only supported by KWP2000."));
+    }
+
+    let mut protocol = if args.flag_all {
+        None
+    } else {
+        Some(init_protocol(&args)?)
+    };
+
+    let mut dtcs = Vec::new();
+    match protocol.as_mut() {
+        Some(p) => {
+            for category in categories.iter() {
+                dtcs.push((Some(*category), p.read_dtcs(*category)?));
+            }
+        }
+        None => dtcs.push((None, init_kwp2000(&args)?.read_all_dtcs()?)),
+    }
+
+    for (category, dtcs) in dtcs.iter() {
+        // Group the DTCs under headers if more than one category was read.
+        if categories.len() > 1 {
+            if let Some(category) = category {
+                println!("\n{}", format!("{} DTCs:", category).to_uppercase().bold());
+            }
+        }
+
+        if dtcs.len() == 0 {
+            println!("\n{}", "No DTCs.".green().bold());
+        }
+
+        for (i, dtc) in dtcs.iter().enumerate() {
+            println!(
+                "\n{}: {} ({})",
+                match dtc.category() {
+                    Some(c) => format!("DTC #{}, {}", i + 1, c),
+                    None => format!("DTC #{}", i + 1),
+                }
+                .green()
+                .bold(),
+                format!("{}", dtc).bold(),
+                dtc.more_info()
+            );
+
+            if args.flag_status {
+                match dtc.status() {
+                    Some(status) => println!("    status: {}", status),
+                    None => println!("    status: unknown"),
+                }
+            }
+
+            print!("{}", dtc.help());
+        }
+    }
+
+    // Insert a newline between the output and the CAN Drop debug log.
+    if args.arg_protocol == Some(Protocol::Can) && args.flag_verbose {
+        println!("");
+    }
+
+    Ok(())
+}
+
+fn cmd_read_j1939_dtcs(args: Args) -> Result<(), Error> {
+    if args.flag_pending || args.flag_permanent || args.flag_all {
+        return Err(Error::new(
+            "J1939 only supports active and previously active DTCs.",
+        ));
+    }
+
+    let mut j1939 = J1939::init(args.flag_bitrate.clone().map(|x| *x))?;
+
+    let dtcs = if args.flag_previous {
+        j1939.read_previously_active_dtcs()?
+    } else {
+        j1939.read_active_dtcs()?
+    };
 
     if dtcs.len() == 0 {
         println!("\n{}", "No DTCs.".green().bold());
@@ -270,11 +803,9 @@ fn cmd_read_dtcs(args: Args) -> Result<(), Error> {
             format!("{}", dtc).bold(),
             dtc.more_info()
         );
-        print!("{}", dtc.help());
     }
 
-    // Insert a newline between the output and the CAN Drop debug log.
-    if args.arg_protocol == Some(Protocol::Can) && args.flag_verbose {
+    if args.flag_verbose {
         println!("");
     }
 
@@ -302,118 +833,854 @@ fn cmd_clear_dtcs(args: Args) -> Result<(), Error> {
     Ok(())
 }
 
+/**
+ * Parse a PID/group given as hex (0x0c) or decimal.
+ */
+fn parse_pid(value: &str) -> Result<u8, std::num::ParseIntError> {
+    if value.starts_with("0x") {
+        u8::from_str_radix(&value[2..], 16)
+    } else {
+        u8::from_str_radix(value, 10)
+    }
+}
+
+/**
+ * Parse a comma-separated list of PIDs/groups.
+ */
+fn parse_pid_list(list: &str) -> Result<Vec<(u8, Option<String>)>, Error> {
+    list.split(',')
+        .map(|value| {
+            parse_pid(value.trim())
+                .map(|pid| (pid, None))
+                .map_err(|e| Error::new(format!("Invalid PID {:?}: {}", value, e)))
+        })
+        .collect()
+}
+
+/**
+ * Read a list of PIDs/groups from a file. Each line contains a PID, either in
+ * hex (0x0c) or decimal, optionally followed by a comment starting with #,
+ * which is returned as the name of the PID. Malformed lines are skipped.
+ */
+fn read_pid_file(path: &str) -> Result<Vec<(u8, Option<String>)>, Error> {
+    let content = std::fs::read_to_string(path)?;
+    let mut pids = Vec::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let (value, comment) = match line.find('#') {
+            Some(index) => (&line[..index], Some(line[(index + 1)..].trim())),
+            None => (line, None),
+        };
+
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        match parse_pid(value) {
+            Ok(pid) => pids.push((pid, comment.filter(|c| !c.is_empty()).map(String::from))),
+            Err(e) => warn!("Skipping line {} of {}: {}", i + 1, path, e),
+        }
+    }
+
+    if pids.is_empty() {
+        return Err(Error::new("No PIDs in PID file."));
+    }
+
+    Ok(pids)
+}
+
+/**
+ * Return the CSV header for the given PIDs, with one column per value.
+ */
+fn csv_header(pids: &[(u8, Option<String>)], readings: &[DiagnosticData]) -> Result<String, Error> {
+    let mut columns = vec![String::from("time")];
+
+    for ((pid, name), data) in pids.iter().zip(readings.iter()) {
+        // Commas would break the CSV, and there's no need for quoting here.
+        let name = match name {
+            Some(n) => n.replace(',', ""),
+            None => format!("0x{:02x}", pid),
+        };
+
+        let count = data.floats()?.len();
+        if count == 1 {
+            columns.push(name);
+        } else {
+            columns.extend((1..=count).map(|i| format!("{} {}", name, i)));
+        }
+    }
+
+    Ok(columns.join(","))
+}
+
+/**
+ * Rename the given log file to <path>.1, shifting previously rotated files
+ * up by one and dropping the oldest, and create a new empty file in its place.
+ */
+fn rotate_log(path: &str) -> Result<File, Error> {
+    for i in (1..LOG_ROTATE_KEEP).rev() {
+        let rotated = format!("{}.{}", path, i);
+        if Path::new(&rotated).exists() {
+            std::fs::rename(&rotated, format!("{}.{}", path, i + 1))?;
+        }
+    }
+
+    std::fs::rename(path, format!("{}.1", path))?;
+    info!("Rotated log file {}.", path);
+
+    Ok(File::create(path)?)
+}
+
+/**
+ * Format the decoded data, optionally followed by the raw bytes.
+ */
+fn format_data(data: &DiagnosticData, show_raw: bool) -> String {
+    if !show_raw {
+        return format!("{}", data);
+    }
+
+    let raw: Vec<String> = data.raw().iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}  [{}]", data, raw.join(" "))
+}
+
+/**
+ * Format the data like [format_data], but label the values of KWP1281 groups
+ * with their field index, like VCDS does.
+ */
+fn format_fields(data: &DiagnosticData, show_raw: bool) -> String {
+    let fields = match data.kwp1281_fields() {
+        Ok(f) if !f.is_empty() && !show_raw => f,
+        _ => {
+            return format_data(data, show_raw);
+        }
+    };
+
+    fields
+        .iter()
+        .map(|(i, value)| format!("[{}] {}", i, value.trim()))
+        .collect::<Vec<String>>()
+        .join("  ")
+}
+
 fn cmd_read_data(args: Args) -> Result<(), Error> {
-    let pid = *args.arg_pid.clone().unwrap();
+    let pids = match (args.flag_pids.as_ref(), args.flag_pid_file.as_ref()) {
+        (Some(list), _) => parse_pid_list(list)?,
+        (_, Some(path)) => read_pid_file(path)?,
+        _ => vec![(*args.arg_pid.clone().unwrap(), None)],
+    };
+
+    if args.flag_periodic {
+        return cmd_read_periodic(args, pids);
+    }
+
+    if args.arg_protocol == Some(Protocol::Kwp2000) {
+        return Err(Error::new("Reading data is not implemented for KWP2000."));
+    }
+
+    // When logging to stdout, the human readable output has to go somewhere
+    // else to keep the CSV stream intact.
+    let log_stdout = args.flag_log.as_ref().map_or(false, |p| p == "-");
+    let mut out: Box<dyn Write> = if log_stdout {
+        colored::control::set_override(false);
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    };
 
     let mut protocol = init_protocol(&args)?;
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    let running = running_flag();
 
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .unwrap();
+    writeln!(out, "")?;
 
-    println!("");
+    if log_stdout && args.flag_log_rotate.is_some() {
+        return Err(Error::new("Can't rotate logs written to stdout."));
+    }
 
-    let mut logfile = None;
-    if let Some(p) = args.flag_log {
-        logfile = Some(File::create(p)?);
+    let mut header_written = false;
+    let mut log_size = 0;
+    let mut logfile: Option<Box<dyn Write>> = None;
+    if log_stdout {
+        logfile = Some(Box::new(stdout()));
+    } else if let Some(p) = args.flag_log.as_ref() {
+        if args.flag_append {
+            let f = OpenOptions::new().create(true).append(true).open(p)?;
+
+            // Previous sessions already wrote a header.
+            log_size = f.metadata()?.len();
+            header_written = log_size > 0;
+            logfile = Some(Box::new(f));
+        } else {
+            logfile = Some(Box::new(File::create(p)?));
+        }
     }
 
-    let start = SystemTime::now();
+    let label = |pid: u8, data: &DiagnosticData| {
+        if args.arg_protocol == Some(Protocol::Kwp1281) {
+            format!("Group {} (0x{:02x})", pid, pid)
+        } else if data.freeze_frame() {
+            format!("PID {} (0x{:02x}, freeze frame)", pid, pid)
+        } else {
+            format!("PID {} (0x{:02x})", pid, pid)
+        }
+    };
+
+    let start = Instant::now();
 
     loop {
-        let data = protocol.read_data(pid, args.flag_freeze_frame)?;
+        let mut readings = Vec::with_capacity(pids.len());
+        for (pid, _) in pids.iter() {
+            let mut attempts = 0;
+            let data = loop {
+                match protocol.read_data(*pid, args.flag_freeze_frame) {
+                    Ok(d) => break d,
+                    Err(e) if e.is_connection_lost() && attempts < MAX_RECONNECT_ATTEMPTS => {
+                        attempts += 1;
+                        protocol = reconnect(&args, protocol, e)?;
+                    }
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            };
+
+            readings.push(data);
+        }
 
         if let Some(f) = logfile.as_mut() {
-            let floats = data.floats()?;
-
-            f.write(
-                format!(
-                    "{},{}\n",
-                    start.elapsed().unwrap().as_secs_f32(),
-                    floats
-                        .iter()
-                        .map(|f| f.to_string())
-                        .collect::<Vec<String>>()
-                        .join(",")
-                )
-                .as_bytes(),
-            )?;
+            if !header_written {
+                let header = format!("{}\n", csv_header(&pids, &readings)?);
+                f.write(header.as_bytes())?;
+                log_size += header.len() as u64;
+                header_written = true;
+            }
+
+            let mut floats = Vec::new();
+            for data in readings.iter() {
+                floats.extend(data.floats()?);
+            }
+
+            let time = match args.flag_timestamp {
+                Some(Timestamp::Rfc3339) => rfc3339(SystemTime::now()),
+                _ => start.elapsed().as_secs_f32().to_string(),
+            };
+
+            let row = format!(
+                "{},{}\n",
+                time,
+                floats
+                    .iter()
+                    .map(|f| f.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            );
+
+            f.write(row.as_bytes())?;
+            f.flush()?;
+            log_size += row.len() as u64;
         }
 
-        print!(
-            "\r{}: {}",
-            if args.arg_protocol == Some(Protocol::Kwp1281) {
-                format!("Group {} (0x{:02x})", pid, pid).green().bold()
-            } else {
-                format!("PID {} (0x{:02x})", pid, pid).green().bold()
-            },
-            data
-        );
+        if let (Some(limit), Some(p)) = (args.flag_log_rotate, args.flag_log.as_ref()) {
+            if log_size >= limit {
+                logfile = Some(Box::new(rotate_log(p)?));
+                log_size = 0;
+                header_written = false;
+            }
+        }
 
-        // Since -v makes protocols print data, staying on the same line
-        // doesn't work anyways, and sometimes it may be desirable to see
-        // previous readings.
-        if args.flag_verbose {
-            println!("");
+        let labels: Vec<String> = pids
+            .iter()
+            .zip(readings.iter())
+            .map(|((pid, _), data)| label(*pid, data))
+            .collect();
+
+        if pids.len() == 1 {
+            write!(
+                out,
+                "\r{}: {}",
+                labels[0].as_str().green().bold(),
+                format_data(&readings[0], args.flag_show_raw)
+            )?;
+        } else {
+            // Multiple PIDs don't fit on a single line, so print one block of
+            // aligned lines per iteration instead.
+            let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+
+            for (label, data) in labels.iter().zip(readings.iter()) {
+                writeln!(
+                    out,
+                    "{} {}",
+                    format!("{:width$}", format!("{}:", label), width = width + 1)
+                        .green()
+                        .bold(),
+                    format_fields(data, args.flag_show_raw)
+                )?;
+            }
         }
 
-        stdout().flush()?;
+        // Since --trace-frames makes protocols print every frame, staying on
+        // the same line doesn't work anyways, and sometimes it may be
+        // desirable to see previous readings.
+        if args.flag_trace_frames || pids.len() > 1 {
+            writeln!(out, "")?;
+        }
+
+        out.flush()?;
 
         if !args.flag_tail || !running.load(Ordering::SeqCst) {
             break;
         }
+
+        protocol.keep_alive()?;
     }
 
-    if !args.flag_verbose {
-        println!("");
+    if !args.flag_trace_frames && pids.len() == 1 {
+        writeln!(out, "")?;
     }
 
     // Insert a newline between the output and the CAN Drop debug log.
     if args.arg_protocol == Some(Protocol::Can) && args.flag_verbose {
-        println!("");
+        writeln!(out, "")?;
     }
 
     Ok(())
 }
 
+/**
+ * Read data pushed by the ECU via periodic transmission, see --periodic.
+ */
+fn cmd_read_periodic(args: Args, pids: Vec<(u8, Option<String>)>) -> Result<(), Error> {
+    if args.arg_protocol != Some(Protocol::Kwp2000) {
+        return Err(Error::new(
+            "Periodic transmission is only supported on KWP2000.",
+        ));
+    }
+
+    let rate = match args.flag_rate {
+        Some(Rate::Slow) => TransmissionRate::Slow,
+        Some(Rate::Medium) => TransmissionRate::Medium,
+        _ => TransmissionRate::Fast,
+    };
+
+    let ids: Vec<u8> = pids.iter().map(|(pid, _)| *pid).collect();
+
+    let mut protocol = init_kwp2000(&args)?;
+
+    // Install the handler before starting the transmission, so Ctrl-C stops
+    // it again instead of killing the process.
+    let running = running_flag();
+
+    protocol.start_periodic(rate, &ids)?;
+    println!("");
+
+    let result = (|| -> Result<(), Error> {
+        while running.load(Ordering::SeqCst) {
+            let (id, data) = protocol.read_periodic()?;
+
+            let name = pids
+                .iter()
+                .find(|(pid, _)| *pid == id)
+                .and_then(|(_, name)| name.clone())
+                .unwrap_or_else(|| format!("PID {} (0x{:02x})", id, id));
+
+            println!("{}: {:02x?}", name.green().bold(), data);
+
+            protocol.keep_alive()?;
+        }
+
+        Ok(())
+    })();
+
+    // Always try to stop the transmission, otherwise the ECU keeps flooding
+    // the K line.
+    if let Err(e) = protocol.stop_periodic() {
+        error!("Failed to stop periodic transmission: {}", e);
+    }
+
+    result
+}
+
 fn cmd_dump_data(args: Args) -> Result<(), Error> {
     let mut protocol = init_protocol(&args)?;
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
+    let running = running_flag();
 
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .unwrap();
+    // Bitmap of present oxygen sensors, used to skip the PIDs for absent ones
+    let mut oxygen_sensors: Option<u8> = None;
+
+    // The supported PIDs only apply to current data, not the freeze frame.
+    let supported = if args.flag_freeze_frame {
+        None
+    } else {
+        protocol.supported_pids().unwrap_or_else(|e| {
+            warn!("Failed to read supported PIDs, reading all: {}", e);
+            None
+        })
+    };
+
+    // Counts and results for the summary, or the JSON output
+    let mut read = 0;
+    let mut skipped = 0;
+    let mut results = Vec::new();
+    let mut errors = Vec::new();
 
     for i in 0x00..=0xff {
         if !running.load(Ordering::SeqCst) {
             break;
         }
 
-        let data = match protocol.read_data(i, args.flag_freeze_frame) {
+        if let Some(pids) = supported.as_ref() {
+            if i != 0x00 && !pids.contains(&i) {
+                skipped += 1;
+                continue;
+            }
+        }
+
+        if let Some(present) = oxygen_sensors {
+            let sensor = match i {
+                0x14..=0x1b => Some(i - 0x14),
+                0x24..=0x2b => Some(i - 0x24),
+                0x34..=0x3b => Some(i - 0x34),
+                _ => None,
+            };
+
+            if let Some(sensor) = sensor {
+                if present & (1 << sensor) == 0 {
+                    skipped += 1;
+                    continue;
+                }
+            }
+        }
+
+        let mut attempts = 0;
+        let result = loop {
+            match protocol.read_data(i, args.flag_freeze_frame) {
+                Err(e)
+                    if e.is_connection_lost()
+                        && args.flag_reconnect
+                        && attempts < MAX_RECONNECT_ATTEMPTS =>
+                {
+                    attempts += 1;
+                    protocol = reconnect(&args, protocol, e)?;
+                }
+                r => break r,
+            }
+        };
+
+        let data = match result {
             Ok(d) => d,
             Err(e) => {
-                error!("Failed to read PID {:02}: {}", i, e);
+                if !args.flag_json {
+                    error!("Failed to read PID {:02}: {}", i, e);
+                }
+                errors.push(json!({"pid": i, "error": e.to_string()}));
                 continue;
             }
         };
 
-        if args.flag_raw {
+        if let Some(present) = data.oxygen_sensors_present() {
+            oxygen_sensors = Some(present);
+        }
+
+        read += 1;
+
+        if args.flag_json {
+            results.push(json!({"pid": i, "value": data.to_string(), "raw": data.raw()}));
+        } else if args.flag_raw {
             println!("{:02x} {:02x?}", i, data.raw());
         } else {
-            println!("{:02x} {}", i, data);
+            println!("{:02x} {}", i, format_data(&data, args.flag_show_raw));
+        }
+    }
+
+    if args.flag_json {
+        let dump = json!({"data": results, "errors": errors, "skipped": skipped});
+        println!("{:#}", dump);
+    } else {
+        println!(
+            "\n{}",
+            format!(
+                "{} PIDs read, {} failed, {} skipped as unsupported.",
+                read,
+                errors.len(),
+                skipped
+            )
+            .bold()
+        );
+    }
+
+    Ok(())
+}
+
+fn cmd_readiness(args: Args) -> Result<(), Error> {
+    if args.arg_protocol == Some(Protocol::Kwp1281) {
+        return Err(Error::new("KWP1281 doesn't support readiness monitors."));
+    }
+
+    if args.arg_protocol == Some(Protocol::Kwp2000) {
+        return Err(Error::new("Reading data is not implemented for KWP2000."));
+    }
+
+    let mut protocol = init_protocol(&args)?;
+    let data = protocol.read_data(0x01, false)?;
+    let status = MonitorStatus::from_obd2_data(data.raw())?;
+
+    let mil = status.mil.unwrap_or(false);
+    let engine = if status.compression_ignition {
+        "compression"
+    } else {
+        "spark"
+    };
+
+    println!("\n{} {}", "MIL:".bold(), if mil { "on" } else { "off" });
+    println!("{} {}", "DTCs:".bold(), status.dtc_count.unwrap_or(0));
+    println!("{} {} ignition", "Engine:".bold(), engine);
+
+    let names = |complete: bool| {
+        let names: Vec<&str> = status
+            .monitors
+            .iter()
+            .filter(|m| m.supported && m.complete == complete)
+            .map(|m| m.name)
+            .collect();
+
+        if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(", ")
+        }
+    };
+
+    println!("\n{} {}", "Complete:".bold(), names(true));
+    println!("{} {}", "Incomplete:".bold(), names(false));
+
+    let allowed = args.flag_allow_incomplete.unwrap_or(0);
+    let incomplete = status.incomplete_monitors().len();
+    if status.ready_for_inspection(allowed) {
+        let verdict = format!(
+            "PASS ({} incomplete monitors, {} allowed)",
+            incomplete, allowed
+        );
+        println!("\n{}", verdict.green().bold());
+    } else if mil {
+        println!("\n{}", "NOT READY (MIL is on)".red().bold());
+    } else {
+        let verdict = format!(
+            "NOT READY ({} incomplete monitors, {} allowed)",
+            incomplete, allowed
+        );
+        println!("\n{}", verdict.red().bold());
+    }
+
+    // Insert a newline between the output and the CAN Drop debug log.
+    if args.arg_protocol == Some(Protocol::Can) && args.flag_verbose {
+        println!("");
+    }
+
+    Ok(())
+}
+
+fn cmd_ecu_reset(args: Args) -> Result<(), Error> {
+    if !confirm(format!("{}: Resetting the ECU while the engine is running may stall it.\nNo warranty. Are you sure you wish to proceed?", "CAUTION".bold().red()))? {
+        return Err(Error::new("Aborting."));
+    }
+
+    let mut protocol = init_protocol(&args)?;
+    protocol.ecu_reset()?;
+
+    println!("\n{}", "ECU reset successfully.".green().bold());
+
+    if !args.flag_resume {
+        return Ok(());
+    }
+
+    let settle = args.flag_settle.unwrap_or(DEFAULT_SETTLE_MILLIS);
+    info!("Waiting {}ms for the ECU to come back.", settle);
+    std::thread::sleep(Duration::from_millis(settle));
+
+    // The CAN bus doesn't have a session to re-establish, so just check
+    // whether the ECU responds again.
+    let result = if args.arg_protocol == Some(Protocol::Can) {
+        protocol.invalidate_cache();
+        protocol.read_data(0x00, false).map(|_| ())
+    } else {
+        // Only check that the ECU accepts a new session, nothing is left to
+        // do with it.
+        drop(protocol);
+        init_protocol(&args).map(|_| ())
+    };
+
+    match result {
+        Ok(()) => println!("{}", "ECU is back.".green().bold()),
+        Err(e) => {
+            return Err(Error::new(format!("ECU did not come back: {}", e)));
         }
     }
 
     Ok(())
 }
 
+/**
+ * Answer a single HTTP request for the serve command with the JSON encoded
+ * data or DTCs.
+ */
+fn serve_request(protocol: &mut Box<dyn Diagnose>, stream: TcpStream) -> Result<(), Error> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // Skip the headers, none of them are needed.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    debug!("Request: {}", request.trim());
+
+    let parts: Vec<&str> = request.split_whitespace().collect();
+    let (status, body) = match parts.as_slice() {
+        ["GET", "/dtcs", ..] => match protocol.read_dtcs(DtcCategory::Stored) {
+            Ok(dtcs) => {
+                let dtcs: Vec<_> = dtcs
+                    .iter()
+                    .map(|dtc| json!({"code": dtc.to_string(), "info": dtc.more_info()}))
+                    .collect();
+                ("200 OK", json!({ "dtcs": dtcs }))
+            }
+            Err(e) => ("500 Internal Server Error", json!({"error": e.to_string()})),
+        },
+        ["GET", path, ..] if path.starts_with("/pid/") => {
+            match parse_pid(&path[5..]).map(|pid| (pid, protocol.read_data(pid, false))) {
+                Ok((pid, Ok(data))) => (
+                    "200 OK",
+                    json!({"pid": pid, "value": data.to_string(), "raw": data.raw()}),
+                ),
+                Ok((_, Err(e))) => ("500 Internal Server Error", json!({"error": e.to_string()})),
+                Err(_) => ("400 Bad Request", json!({"error": "Invalid PID."})),
+            }
+        }
+        _ => ("404 Not Found", json!({"error": "Not found."})),
+    };
+
+    let body = body.to_string();
+    write!(
+        reader.get_mut(),
+        "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+fn cmd_serve(args: Args) -> Result<(), Error> {
+    if args.arg_protocol == Some(Protocol::Kwp2000) {
+        return Err(Error::new("Reading data is not implemented for KWP2000."));
+    }
+
+    let mut protocol = init_protocol(&args)?;
+
+    let address = args
+        .flag_listen
+        .clone()
+        .unwrap_or_else(|| String::from(DEFAULT_LISTEN_ADDRESS));
+
+    // Poll for connections, so the running flag is checked regularly.
+    let listener = TcpListener::bind(&address)?;
+    listener.set_nonblocking(true)?;
+
+    info!("Serving data on http://{}/", address);
+
+    let running = running_flag();
+    while running.load(Ordering::SeqCst) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                if let Err(e) = protocol.keep_alive() {
+                    error!("Keep-alive failed: {}", e);
+                    protocol = reinit_protocol(&args, protocol, e)?;
+                }
+
+                std::thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        // A single failed request shouldn't stop the server.
+        if let Err(e) = serve_request(&mut protocol, stream) {
+            warn!("Failed to answer request: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn cmd_dtc_status(args: Args) -> Result<(), Error> {
+    let code = *args.arg_code.clone().unwrap();
+
+    let mut protocol = init_kwp2000(&args)?;
+
+    match protocol.read_dtc_status(code)? {
+        Some(status) => println!(
+            "\n{}: {}",
+            format!("OEM Code {:05}", code).green().bold(),
+            status
+        ),
+        None => println!(
+            "\n{}: {}",
+            format!("OEM Code {:05}", code).green().bold(),
+            "Not stored".bold()
+        ),
+    }
+
+    Ok(())
+}
+
+fn cmd_read_identifier(args: Args) -> Result<(), Error> {
+    let id = *args.arg_id.clone().unwrap();
+
+    let mut protocol = init_kwp2000(&args)?;
+    let data = protocol.read_common_identifier(id)?;
+
+    println!(
+        "\n{}: {:02x?}",
+        format!("Identifier 0x{:04x}", id).green().bold(),
+        data
+    );
+
+    Ok(())
+}
+
+/**
+ * Unlock the ECU for memory transfers by requesting a seed and sending the key,
+ * either computed by the given algorithm or entered by the user.
+ */
+fn security_access(
+    protocol: &mut Kwp2000,
+    level: u8,
+    algorithm: Option<&dyn SeedKey>,
+) -> Result<(), Error> {
+    if level % 2 == 0 {
+        return Err(Error::new("Security access level has to be odd."));
+    }
+
+    if let Some(algorithm) = algorithm {
+        protocol.security_access(level, algorithm)?;
+        info!("Security access granted.");
+        return Ok(());
+    }
+
+    let seed = protocol.request_seed(level)?;
+    if seed.iter().all(|b| *b == 0) {
+        info!("ECU is already unlocked.");
+        return Ok(());
+    }
+
+    let hex: Vec<String> = seed.iter().map(|b| format!("{:02x}", b)).collect();
+    println!("{}: {}", "Seed".green().bold(), hex.join(" "));
+
+    let key = parse_hex_bytes(&prompt("Key (hex)".to_string())?)?;
+
+    protocol.send_key(level, &key)?;
+    info!("Security access granted.");
+
+    Ok(())
+}
+
+/**
+ * Parse a memory address or size, which are limited to 24 bits.
+ */
+fn memory_arg(arg: &Option<HexInput64>) -> Result<u32, Error> {
+    let value = *arg.clone().unwrap();
+    if value > 0xffffff {
+        return Err(Error::new(
+            "Memory address and size are limited to 24 bits.",
+        ));
+    }
+
+    Ok(value as u32)
+}
+
+fn cmd_upload(args: Args) -> Result<(), Error> {
+    let address = memory_arg(&args.arg_address)?;
+    let size = memory_arg(&args.arg_size)?;
+    let path = args.arg_file.clone().unwrap();
+    let level = args
+        .flag_security_level
+        .clone()
+        .map_or(DEFAULT_SECURITY_LEVEL, |l| *l);
+    let algorithm = match args.flag_seed_key.as_ref() {
+        Some(name) => Some(seed_key_by_name(name)?),
+        None => None,
+    };
+
+    if !confirm(format!("{}: Reading ECU memory requires security access, which may lock out the ECU for a while if the key is wrong.\nNo warranty. Are you sure you wish to proceed?", "CAUTION".bold().red()))? {
+        return Err(Error::new("Aborting."));
+    }
+
+    let mut protocol = init_kwp2000(&args)?;
+    security_access(&mut protocol, level, algorithm.as_deref())?;
+
+    // Only create the file once unlocking worked, so a failed attempt doesn't
+    // leave an empty one behind.
+    let mut file = File::create(&path)?;
+
+    info!("Reading {} bytes from 0x{:06x}...", size, address);
+    let data = protocol.upload(address, size)?;
+    file.write_all(&data)?;
+
+    info!("Wrote {} bytes to {}.", data.len(), path);
+
+    Ok(())
+}
+
+fn cmd_download(args: Args) -> Result<(), Error> {
+    let address = memory_arg(&args.arg_address)?;
+    let path = args.arg_file.clone().unwrap();
+    let level = args
+        .flag_security_level
+        .clone()
+        .map_or(DEFAULT_SECURITY_LEVEL, |l| *l);
+    let algorithm = match args.flag_seed_key.as_ref() {
+        Some(name) => Some(seed_key_by_name(name)?),
+        None => None,
+    };
+
+    let data = std::fs::read(&path)?;
+    if data.is_empty() || data.len() > 0xffffff {
+        return Err(Error::new(
+            "File has to contain between 1 and 0xffffff bytes.",
+        ));
+    }
+
+    if !confirm(format!("{}: About to overwrite {} bytes of ECU memory at 0x{:06x}. Writing the wrong data or interrupting the transfer will likely leave the ECU unusable, which may prevent the vehicle from starting or cause it to behave dangerously.\nNo warranty. Are you absolutely sure you wish to proceed?", "CAUTION".bold().red(), data.len(), address))? {
+        return Err(Error::new("Aborting."));
+    }
+
+    if prompt(format!("Type {} to confirm", "download".bold()))? != "download" {
+        return Err(Error::new("Aborting."));
+    }
+
+    let mut protocol = init_kwp2000(&args)?;
+    security_access(&mut protocol, level, algorithm.as_deref())?;
+
+    info!("Writing {} bytes to 0x{:06x}...", data.len(), address);
+    protocol.download(address, &data)?;
+
+    info!("Download complete.");
+
+    Ok(())
+}
+
 fn cmd_adaptation(args: Args) -> Result<(), Error> {
     let pid = *args.arg_pid.clone().unwrap();
     let value = args.arg_value.clone().map(|x| x.into());
@@ -455,6 +1722,43 @@ fn cmd_adaptation(args: Args) -> Result<(), Error> {
     Ok(())
 }
 
+fn cmd_measuring_block(args: Args) -> Result<(), Error> {
+    let pid = *args.arg_pid.clone().unwrap();
+
+    let mut protocol = init_kwp1281(&args)?;
+    let data = protocol.read_data(pid, false)?;
+
+    let label = format!("Group {} (0x{:02x})", pid, pid);
+    println!("\n{}", label.green().bold());
+
+    // Groups consisting of a single string have no fields.
+    let fields = match data.kwp1281_fields() {
+        Ok(f) => f,
+        Err(_) => {
+            println!("    {}", data);
+            return Ok(());
+        }
+    };
+
+    if fields.is_empty() {
+        println!("    No data");
+    }
+
+    let chunks: Vec<&[u8]> = data.raw().chunks(3).collect();
+    for (i, value) in fields.iter() {
+        let chunk = chunks[i - 1];
+        let formula = kwp1281_formula(chunk[0]).unwrap_or("unknown formula");
+
+        print!("    {}: {:16} ({})", i, value.trim(), formula);
+        if args.flag_show_raw {
+            print!("  [{:02x} {:02x} {:02x}]", chunk[0], chunk[1], chunk[2]);
+        }
+        println!("");
+    }
+
+    Ok(())
+}
+
 fn cmd_basic_setting(args: Args) -> Result<(), Error> {
     let pid = *args.arg_pid.clone().unwrap();
 
@@ -466,13 +1770,7 @@ fn cmd_basic_setting(args: Args) -> Result<(), Error> {
 
     let mut protocol = init_kwp1281(&args)?;
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .unwrap();
+    let running = running_flag();
 
     println!("");
 
@@ -484,10 +1782,10 @@ fn cmd_basic_setting(args: Args) -> Result<(), Error> {
             data
         );
 
-        // Since -v makes protocols print data, staying on the same line
-        // doesn't work anyways, and sometimes it may be desirable to see
-        // previous readings.
-        if args.flag_verbose {
+        // Since --trace-frames makes protocols print every frame, staying on
+        // the same line doesn't work anyways, and sometimes it may be
+        // desirable to see previous readings.
+        if args.flag_trace_frames {
             println!("");
         }
 
@@ -496,9 +1794,11 @@ fn cmd_basic_setting(args: Args) -> Result<(), Error> {
         if !running.load(Ordering::SeqCst) {
             break;
         }
+
+        protocol.keep_alive()?;
     }
 
-    if !args.flag_verbose {
+    if !args.flag_trace_frames {
         println!("");
     }
 
@@ -506,26 +1806,36 @@ fn cmd_basic_setting(args: Args) -> Result<(), Error> {
 }
 
 fn cmd_simulator(args: Args) -> Result<(), Error> {
+    let bitrate = args.flag_bitrate.clone().map(|x| *x);
+
     match args.arg_protocol.unwrap() {
-        Protocol::Can => CanBus::run_simulator(args.flag_bitrate.unwrap_or(500000)),
-        Protocol::Kwp1281 => Kwp1281::run_simulator(args.flag_bitrate.unwrap_or(9600)),
+        Protocol::Can => CanBus::run_simulator(bitrate.unwrap_or(500000)),
+        Protocol::Kwp1281 => Kwp1281::run_simulator(bitrate.unwrap_or(9600)),
         Protocol::Iso9141 => unimplemented!(),
         Protocol::Kwp2000 => todo!(),
+        Protocol::J1939 => unreachable!(),
     }
 }
 
 fn cmd_test_hardware(args: Args) -> Result<(), Error> {
-    KLine::test_hardware(args.cmd_tx, args.flag_bitrate.unwrap_or(9600))
+    KLine::test_hardware(args.cmd_tx, args.flag_bitrate.clone().map_or(9600, |x| *x))
 }
 
 fn do_main() -> Result<(), Error> {
-    let args: Args = Docopt::new(USAGE)
+    let mut args: Args = Docopt::new(USAGE)
         .map(|d| d.version(Some(VERSION.into())))
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    let log_json = args.flag_log_json;
     env_logger::Builder::new()
-        .format(|buf, record| {
+        .format(move |buf, record| {
+            if log_json {
+                let level = record.level().to_string().to_lowercase();
+                let msg = record.args().to_string();
+                return writeln!(buf, "{}", json!({"level": level, "msg": msg}));
+            }
+
             writeln!(
                 buf,
                 "{}: {}",
@@ -547,13 +1857,37 @@ fn do_main() -> Result<(), Error> {
                 LevelFilter::Info
             },
         )
+        .filter(
+            Some(FRAME_LOG_TARGET),
+            if args.flag_trace_frames {
+                LevelFilter::Trace
+            } else {
+                LevelFilter::Off
+            },
+        )
         .init();
 
+    Config::load()?.apply(&mut args);
+
+    if let Some(cmd) = args.flag_sudo_cmd.as_ref() {
+        set_sudo_cmd(cmd);
+    }
+
+    if let Some(delay) = args.flag_write_delay {
+        set_default_write_delay(delay);
+    }
+
     if args.arg_protocol == Some(Protocol::Iso9141) {
         return Err(Error::new("Protocol currently unimplemented."));
     }
 
-    if args.cmd_read_dtcs {
+    if args.arg_protocol == Some(Protocol::J1939) && !args.cmd_read_dtcs {
+        return Err(Error::new("J1939 only supports reading DTCs."));
+    }
+
+    let timing = args.flag_timing;
+
+    let result = if args.cmd_read_dtcs {
         cmd_read_dtcs(args)
     } else if args.cmd_clear_dtcs {
         cmd_clear_dtcs(args)
@@ -561,8 +1895,24 @@ fn do_main() -> Result<(), Error> {
         cmd_read_data(args)
     } else if args.cmd_dump_data {
         cmd_dump_data(args)
+    } else if args.cmd_readiness {
+        cmd_readiness(args)
+    } else if args.cmd_ecu_reset {
+        cmd_ecu_reset(args)
+    } else if args.cmd_serve {
+        cmd_serve(args)
+    } else if args.cmd_dtc_status {
+        cmd_dtc_status(args)
+    } else if args.cmd_read_identifier {
+        cmd_read_identifier(args)
+    } else if args.cmd_upload {
+        cmd_upload(args)
+    } else if args.cmd_download {
+        cmd_download(args)
     } else if args.cmd_adaptation {
         cmd_adaptation(args)
+    } else if args.cmd_measuring_block {
+        cmd_measuring_block(args)
     } else if args.cmd_basic_setting {
         cmd_basic_setting(args)
     } else if args.cmd_simulator {
@@ -571,17 +1921,93 @@ fn do_main() -> Result<(), Error> {
         cmd_test_hardware(args)
     } else {
         unreachable!()
+    };
+
+    if timing {
+        info!("Query timing: {}", latency_stats());
     }
+
+    result
+}
+
+/**
+ * Run the given command, and clean up after it even if it failed or
+ * panicked. Returns the exit code.
+ */
+fn run_with_cleanup<F>(command: F) -> i32
+where
+    F: FnOnce() -> Result<(), Error> + std::panic::UnwindSafe,
+{
+    let result = std::panic::catch_unwind(command);
+
+    // The CanBus is normally dropped before getting here, but make sure the
+    // interface doesn't stay up in any case.
+    shutdown_interface();
+
+    let code = match result {
+        Ok(Ok(())) => {
+            return 0;
+        }
+        Ok(Err(e)) => {
+            error!("{}", e);
+            1
+        }
+        // The panic message was already printed by the panic hook.
+        Err(_) => 101,
+    };
+
+    // Reset pin modes, if the K line was used at all
+    reset_pin_modes();
+
+    code
 }
 
 fn main() {
-    if let Err(e) = do_main() {
-        error!("{}", e);
+    let code = run_with_cleanup(do_main);
 
-        // Reset pin modes
-        set_pin_mode(9, 24, PinMode::Uart).unwrap();
-        set_pin_mode(9, 26, PinMode::Uart).unwrap();
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::value::{Error as ValueError, StrDeserializer};
+    use serde::de::IntoDeserializer;
+
+    fn hex16(s: &str) -> Result<u16, ValueError> {
+        let deserializer: StrDeserializer<ValueError> = s.into_deserializer();
+        HexInput16::deserialize(deserializer).map(|h| *h)
+    }
+
+    #[test]
+    fn hex_input_16() {
+        assert_eq!(hex16("0x1234"), Ok(0x1234));
+        assert_eq!(hex16("0x12"), Ok(0x12));
+        assert_eq!(hex16("0x123"), Ok(0x123));
+        assert_eq!(hex16("4660"), Ok(4660));
+    }
+
+    #[test]
+    fn interface_down_after_failure() {
+        // No interface is actually brought up or down.
+        set_interface("rustbucket-test0");
+        set_sudo_cmd("true");
+
+        mark_interface_up();
+        assert_eq!(run_with_cleanup(|| panic!("Panicking.")), 101);
+        assert!(!interface_up());
+
+        mark_interface_up();
+        assert_eq!(run_with_cleanup(|| Err(Error::new("Interrupted."))), 1);
+        assert!(!interface_up());
+    }
 
-        std::process::exit(1);
+    #[test]
+    fn hex_input_16_invalid() {
+        assert!(hex16("0x").is_err());
+        assert!(hex16("0x12345").is_err());
+        assert!(hex16("0xzz").is_err());
     }
 }
@@ -3,16 +3,39 @@
 use std::fs::File;
 use std::io::Write;
 use std::process::Command;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
 
 use colored::*;
 use gpio_cdev::LineHandle;
-use log::debug;
+use log::{debug, warn};
 use nix;
 
 use crate::error::*;
 
+/// Log target of all frames sent and received, traced separately from the
+/// remaining log messages
+pub const FRAME_LOG_TARGET: &str = "frames";
+
+/// Cleared once SIGINT or SIGTERM is received, see [running_flag]
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+/// Guard for installing the signal handler only once
+static SIGNAL_HANDLER: Once = Once::new();
+
+/// Programs for running commands as root, in order of preference
+const SUDO_CMDS: [&str; 3] = ["sudo", "doas", "pkexec"];
+
+/// Program for running commands as root set by the user, see [set_sudo_cmd]
+static SUDO_CMD: Mutex<Option<String>> = Mutex::new(None);
+
+/// Round trip times of all queries, see [record_latency]
+static LATENCY: Mutex<LatencyStats> = Mutex::new(LatencyStats::new());
+
 /// Pin multiplexer mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum PinMode {
     /// Set pin to GPIO mode
     Gpio,
@@ -29,13 +52,116 @@ impl Into<String> for PinMode {
     }
 }
 
+/**
+ * Return a flag that is cleared once the program is interrupted with Ctrl-C
+ * (SIGINT) or stopped by a service manager (SIGTERM), so long-running loops
+ * can exit gracefully and e.g. bring down the CAN interface. The handler is
+ * installed on the first call, since ctrlc only supports a single one.
+ */
+pub fn running_flag() -> &'static AtomicBool {
+    SIGNAL_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            RUNNING.store(false, Ordering::SeqCst);
+        })
+        .unwrap();
+    });
+
+    &RUNNING
+}
+
+/**
+ * Minimum, average and maximum round trip times of queries, i.e. the time
+ * from sending a request until the response is complete.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct LatencyStats {
+    /// Number of recorded queries
+    pub count: u32,
+    /// Shortest round trip time
+    pub min: Duration,
+    /// Longest round trip time
+    pub max: Duration,
+    /// Sum of all round trip times
+    pub total: Duration,
+}
+
+impl LatencyStats {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+            total: Duration::ZERO,
+        }
+    }
+
+    /**
+     * Add the round trip time of a single query.
+     */
+    pub fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+        self.total += latency;
+    }
+
+    /**
+     * Return the average round trip time, if any queries were recorded.
+     */
+    pub fn average(&self) -> Option<Duration> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.total / self.count)
+        }
+    }
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.average() {
+            Some(average) => write!(
+                f,
+                "{} queries, min {:.1} ms, avg {:.1} ms, max {:.1} ms",
+                self.count,
+                self.min.as_secs_f64() * 1000.0,
+                average.as_secs_f64() * 1000.0,
+                self.max.as_secs_f64() * 1000.0
+            ),
+            None => write!(f, "no queries"),
+        }
+    }
+}
+
+/**
+ * Log the round trip time of a query, and add it to the statistics returned
+ * by [latency_stats].
+ */
+pub fn record_latency(latency: Duration) {
+    debug!("Query took {:.1} ms", latency.as_secs_f64() * 1000.0);
+    LATENCY.lock().unwrap().record(latency);
+}
+
+/**
+ * Return the statistics of all round trip times recorded so far.
+ */
+pub fn latency_stats() -> LatencyStats {
+    *LATENCY.lock().unwrap()
+}
+
 /**
  * Busily wait until the given number of microseconds has elapsed since the
  * given reference. Used instead of sleeping for some of the software
  * UART stuff.
  */
-pub fn busy_wait<T: Into<u128> + Copy>(reference: SystemTime, elapsed_micros: T) {
-    while reference.elapsed().unwrap().as_micros() < elapsed_micros.into() {}
+pub fn busy_wait<T: Into<u128> + Copy>(reference: Instant, elapsed_micros: T) {
+    while reference.elapsed().as_micros() < elapsed_micros.into() {}
 }
 
 /**
@@ -46,8 +172,8 @@ pub fn busy_wait_until<T: Into<u128> + Copy>(
     value: u8,
     timeout_micros: T,
 ) -> Result<(), Error> {
-    let start = SystemTime::now();
-    while start.elapsed().unwrap().as_micros() < timeout_micros.into() {
+    let start = Instant::now();
+    while start.elapsed().as_micros() < timeout_micros.into() {
         if rx.get_value()? == value {
             return Ok(());
         }
@@ -57,19 +183,88 @@ pub fn busy_wait_until<T: Into<u128> + Copy>(
 }
 
 /**
- * Set the given pin's pin multiplexer mode.
+ * Set a pin's pin multiplexer mode by writing to the given state file.
  */
-pub fn set_pin_mode(chip: u32, pin: u32, mode: PinMode) -> Result<(), Error> {
-    let path = format!(
-        "/sys/devices/platform/ocp/ocp:P{}_{}_pinmux/state",
-        chip, pin
-    );
-    let mut f = File::create(path)?;
+pub fn set_pin_mode(path: &str, mode: PinMode) -> Result<(), Error> {
+    let context = |e: std::io::Error| {
+        Error::new(format!(
+            "Failed to set pin mode via {} ({}). Check that the file exists and is writable, e.g. by running as root. Boards other than the BeagleBone Blue may not have it.",
+            path, e
+        ))
+    };
+
+    let mut f = File::create(path).map_err(context)?;
     let string: String = mode.into();
-    f.write(&string.into_bytes())?;
+    f.write(&string.into_bytes()).map_err(context)?;
     Ok(())
 }
 
+/**
+ * Call the given function until it succeeds, at most the given number of
+ * times, sleeping for the given duration in between. Used for flaky
+ * procedures such as the K line initialization.
+ */
+pub fn retry<T, F: FnMut() -> Result<T, Error>>(
+    attempts: u32,
+    delay: Duration,
+    mut f: F,
+) -> Result<T, Error> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(x) => {
+                return Ok(x);
+            }
+            Err(e) if attempt < attempts => {
+                warn!("{} Retrying ({}/{})...", e, attempt, attempts - 1);
+                sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+}
+
+/**
+ * Format the given time as an RFC 3339 UTC timestamp with millisecond
+ * precision, e.g. 2024-01-02T03:04:05.678Z.
+ */
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86400) as i64;
+    let seconds_of_day = secs % 86400;
+
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day / 60) % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
 /**
  * Ask user for confirmation with the given message.
  */
@@ -84,21 +279,89 @@ pub fn confirm(msg: String) -> Result<bool, Error> {
 }
 
 /**
- * Run the given command as root, by using sudo if necessary.
+ * Ask user for input with the given message, returning the entered line.
  */
-pub fn run_cmd_as_root<T: Into<String>>(cmd: T) -> Result<(), Error> {
-    let mut cmd = cmd.into();
-    if !nix::unistd::getuid().is_root() {
-        cmd = format!("sudo {}", cmd);
+pub fn prompt(msg: String) -> Result<String, Error> {
+    let mut stdout = std::io::stdout();
+    print!("{}: ", msg);
+    stdout.flush()?;
+
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+/**
+ * Parse bytes given in hex, e.g. "12 34 ab" or "1234ab".
+ */
+pub fn parse_hex_bytes(input: &str) -> Result<Vec<u8>, Error> {
+    let digits: String = input.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.is_empty() || digits.len() % 2 != 0 {
+        return Err(Error::new("Expected bytes in hex."));
     }
 
-    debug!("$ {}", cmd);
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| Error::new("Expected bytes in hex."))
+}
+
+/**
+ * Set the program used by [run_cmd_as_root] to gain root privileges, instead
+ * of detecting one.
+ */
+pub fn set_sudo_cmd(cmd: &str) {
+    *SUDO_CMD.lock().unwrap() = Some(String::from(cmd));
+}
+
+/**
+ * Return the program to use for gaining root privileges, either the one set
+ * via [set_sudo_cmd], or the first of [SUDO_CMDS] found in the PATH.
+ */
+fn sudo_cmd() -> Option<String> {
+    if let Some(cmd) = SUDO_CMD.lock().unwrap().as_ref() {
+        return Some(cmd.clone());
+    }
+
+    let path = std::env::var_os("PATH")?;
+    SUDO_CMDS
+        .iter()
+        .find(|cmd| std::env::split_paths(&path).any(|dir| dir.join(cmd).is_file()))
+        .map(|cmd| String::from(*cmd))
+}
+
+/**
+ * Run the given program with the given arguments as root, by using sudo or
+ * one of the alternatives if necessary.
+ */
+pub fn run_cmd_as_root(program: &str, args: &[&str]) -> Result<(), Error> {
+    let mut cmd = if nix::unistd::getuid().is_root() {
+        Command::new(program)
+    } else {
+        let sudo = sudo_cmd().ok_or_else(|| {
+            Error::new(format!(
+                "Running {} requires root privileges, but none of {} was found. Run as root or use --sudo-cmd.",
+                program,
+                SUDO_CMDS.join(", ")
+            ))
+        })?;
+
+        let mut cmd = Command::new(sudo);
+        cmd.arg(program);
+        cmd
+    };
+    cmd.args(args);
+
+    debug!("$ {:?}", cmd);
 
-    let chunks: Vec<&str> = cmd.split(" ").collect();
-    let status = Command::new(chunks[0]).args(&chunks[1..]).status()?;
+    let status = cmd.status()?;
 
     if !status.success() {
-        return Err(Error::new("Failed to initialize CAN interface."));
+        return Err(Error::new(format!(
+            "Command {} failed ({}).",
+            program, status
+        )));
     }
 
     Ok(())
@@ -1,5 +1,7 @@
 //! General diagnosis implementation for all OBD2 protocols
 
+use log::warn;
+
 use crate::diagnose::*;
 use crate::error::*;
 
@@ -13,20 +15,77 @@ pub trait Obd2Protocol {
      * (service + 0x40) and the args/PIDs, leaving just the value.
      */
     fn obd_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /**
+     * Sends a query like [Obd2Protocol::obd_query], but only to the ECU that
+     * answers OBD queries instead of all ECUs, for services that must not
+     * affect every ECU on the bus.
+     */
+    fn ecu_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /**
+     * Return the storage for caching the supported PIDs, see
+     * [Diagnose::supported_pids].
+     */
+    fn supported_pids_cache(&mut self) -> &mut Option<Vec<u8>>;
+}
+
+/**
+ * Query the bitmaps of supported PIDs (PIDs 0x00, 0x20, ...), each of which
+ * also indicates whether the next bitmap is supported.
+ */
+fn query_supported_pids<T: Obd2Protocol + ?Sized>(protocol: &mut T) -> Result<Vec<u8>, Error> {
+    let mut pids = Vec::new();
+
+    let mut base: u8 = 0x00;
+    loop {
+        let bitmap = protocol.obd_query(0x01, &[base])?;
+        if bitmap.len() < 4 {
+            return Err(Error::new("Unexpected data length."));
+        }
+
+        for i in 0..32 {
+            // The last bit of the last bitmap would be PID 0x100.
+            let pid = base as u16 + 1 + i as u16;
+            if pid <= 0xff && bitmap[i / 8] & (0x80 >> (i % 8)) != 0 {
+                pids.push(pid as u8);
+            }
+        }
+
+        // The last PID of each range is the next bitmap.
+        if base == 0xe0 || !pids.contains(&(base + 0x20)) {
+            break;
+        }
+
+        base += 0x20;
+    }
+
+    Ok(pids)
 }
 
 impl<T: Obd2Protocol> Diagnose for T {
-    fn read_dtcs(&mut self, pending: bool) -> Result<Vec<DiagnosticTroubleCode>, Error> {
-        let service = if pending { 0x07 } else { 0x03 };
-        let response = self.obd_query(service, &[])?;
+    fn read_dtcs(&mut self, category: DtcCategory) -> Result<Vec<DiagnosticTroubleCode>, Error> {
+        let response = self.obd_query(category.obd2_service(), &[])?;
+
+        // ECUs without any DTCs may return just the service identifier, or a
+        // count of 0.
+        if response.is_empty() || response[0] == 0 {
+            return Ok(Vec::new());
+        }
 
         let _count = response[0];
         let data = response[1..].to_vec();
 
+        // Some ECUs pad the response with a trailing byte, ignore it.
+        let chunks = data.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            warn!("Ignoring trailing DTC data: {:02x?}", chunks.remainder());
+        }
+
         let mut dtcs = Vec::new();
-        for chunk in data.chunks(2) {
+        for chunk in chunks {
             let code = ((chunk[0] as u16) << 8) + (chunk[1] as u16);
-            dtcs.push(DiagnosticTroubleCode::Obd(code));
+            dtcs.push(DiagnosticTroubleCode::Obd(code, Some(category)));
         }
 
         Ok(dtcs)
@@ -34,6 +93,7 @@ impl<T: Obd2Protocol> Diagnose for T {
 
     fn clear_dtcs(&mut self) -> Result<(), Error> {
         self.obd_query(0x04, &[])?;
+        self.invalidate_cache();
         Ok(())
     }
 
@@ -41,6 +101,121 @@ impl<T: Obd2Protocol> Diagnose for T {
         let service = if freeze_frame { 0x02 } else { 0x01 };
         let data = self.obd_query(service, &[pid])?;
 
-        Ok(DiagnosticData::from_obd2_data(pid, data))
+        Ok(DiagnosticData::from_obd2_data(service, pid, data))
+    }
+
+    fn supported_pids(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if self.supported_pids_cache().is_none() {
+            let pids = query_supported_pids(self)?;
+            *self.supported_pids_cache() = Some(pids);
+        }
+
+        Ok(self.supported_pids_cache().clone())
+    }
+
+    fn invalidate_cache(&mut self) {
+        *self.supported_pids_cache() = None;
+    }
+
+    fn ecu_reset(&mut self) -> Result<(), Error> {
+        // Not part of OBD2, but answered by ECUs supporting ISO 14229 (UDS).
+        self.ecu_query(0x11, &[0x01])?;
+        self.invalidate_cache();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ECU answering OBD queries with fixed responses per service
+    struct MockEcu {
+        responses: Vec<(u8, Vec<u8>)>,
+        supported_pids: Option<Vec<u8>>,
+        /// Services sent to all ECUs
+        functional: Vec<u8>,
+    }
+
+    impl MockEcu {
+        fn new(responses: Vec<(u8, Vec<u8>)>) -> Self {
+            Self {
+                responses,
+                supported_pids: None,
+                functional: Vec::new(),
+            }
+        }
+    }
+
+    impl Obd2Protocol for MockEcu {
+        fn obd_query(&mut self, service: u8, args: &[u8]) -> Result<Vec<u8>, Error> {
+            self.functional.push(service);
+            self.ecu_query(service, args)
+        }
+
+        fn ecu_query(&mut self, service: u8, _args: &[u8]) -> Result<Vec<u8>, Error> {
+            self.responses
+                .iter()
+                .find(|(s, _)| *s == service)
+                .map(|(_, r)| r.clone())
+                .ok_or_else(|| Error::new("No response."))
+        }
+
+        fn supported_pids_cache(&mut self) -> &mut Option<Vec<u8>> {
+            &mut self.supported_pids
+        }
+    }
+
+    #[test]
+    fn ecu_reset_not_functional() {
+        let mut ecu = MockEcu::new(vec![(0x11, vec![])]);
+        ecu.ecu_reset().unwrap();
+        assert!(!ecu.functional.contains(&0x11));
+    }
+
+    #[test]
+    fn no_dtcs() {
+        let mut ecu = MockEcu::new(vec![(0x03, vec![0x00])]);
+        assert!(ecu.read_dtcs(DtcCategory::Stored).unwrap().is_empty());
+
+        let mut ecu = MockEcu::new(vec![(0x03, vec![])]);
+        assert!(ecu.read_dtcs(DtcCategory::Stored).unwrap().is_empty());
+    }
+
+    #[test]
+    fn odd_length_dtcs() {
+        let mut ecu = MockEcu::new(vec![(0x07, vec![0x01, 0x01, 0x71, 0x00])]);
+
+        // The trailing padding byte is ignored.
+        assert_eq!(
+            ecu.read_dtcs(DtcCategory::Pending).unwrap(),
+            vec![DiagnosticTroubleCode::Obd(
+                0x0171,
+                Some(DtcCategory::Pending)
+            )]
+        );
+    }
+
+    #[test]
+    fn supported_pids_last_bitmap() {
+        let mut ecu = MockEcu::new(vec![(0x01, vec![0xff, 0xff, 0xff, 0xff])]);
+
+        let pids = ecu.supported_pids().unwrap().unwrap();
+        assert_eq!(pids.len(), 255);
+        assert_eq!(pids[0], 0x01);
+        assert_eq!(pids[254], 0xff);
+    }
+
+    #[test]
+    fn stored_dtcs() {
+        let mut ecu = MockEcu::new(vec![(0x03, vec![0x02, 0x01, 0x71, 0x03, 0x00])]);
+
+        assert_eq!(
+            ecu.read_dtcs(DtcCategory::Stored).unwrap(),
+            vec![
+                DiagnosticTroubleCode::Obd(0x0171, Some(DtcCategory::Stored)),
+                DiagnosticTroubleCode::Obd(0x0300, Some(DtcCategory::Stored)),
+            ]
+        );
     }
 }
@@ -0,0 +1,115 @@
+//! Seed-to-key algorithms for security access
+//!
+//! The key the ECU expects in response to a seed is manufacturer and often
+//! ECU specific. Algorithms implement [SeedKey], and a few known ones are
+//! available by name via [seed_key_by_name].
+
+use crate::error::*;
+use crate::misc::*;
+
+/// Names of the built-in algorithms accepted by [seed_key_by_name]
+pub const SEED_KEY_NAMES: &[&'static str] = &["vag-add", "vag-rotate", "constant:<hex>"];
+
+/// Offset added to the seed by [VagAdd] by default
+const VAG_ADD_OFFSET: u32 = 0x0001_1170;
+
+/// Number of rounds of [VagRotate]
+const VAG_ROTATE_ROUNDS: usize = 5;
+
+/// Value XORed into the key by [VagRotate] when the top bit is shifted out
+const VAG_ROTATE_XOR: u32 = 0x5fbd_5dbd;
+
+/// Trait for computing the key for a security access seed.
+pub trait SeedKey {
+    /**
+     * Compute the key for the given seed, which was requested for the given
+     * security access level (the odd request level, not the key level).
+     */
+    fn compute(&self, seed: &[u8], level: u8) -> Vec<u8>;
+}
+
+/**
+ * Interpret up to 4 seed bytes as a big-endian number, as used by most VAG
+ * ECUs.
+ */
+fn seed_u32(seed: &[u8]) -> u32 {
+    seed.iter().take(4).fold(0, |acc, b| (acc << 8) | *b as u32)
+}
+
+/// Key is the seed plus a fixed offset, used for the level 1 login of many
+/// VAG KWP2000 ECUs.
+pub struct VagAdd {
+    /// Offset added to the seed
+    pub offset: u32,
+}
+
+impl Default for VagAdd {
+    fn default() -> Self {
+        Self {
+            offset: VAG_ADD_OFFSET,
+        }
+    }
+}
+
+impl SeedKey for VagAdd {
+    fn compute(&self, seed: &[u8], _level: u8) -> Vec<u8> {
+        seed_u32(seed)
+            .wrapping_add(self.offset)
+            .to_be_bytes()
+            .to_vec()
+    }
+}
+
+/// Key is the seed shifted left over several rounds, XORing in a constant
+/// whenever the top bit is shifted out. Used by some Bosch ME7 ECUs in VAG
+/// vehicles.
+pub struct VagRotate;
+
+impl SeedKey for VagRotate {
+    fn compute(&self, seed: &[u8], _level: u8) -> Vec<u8> {
+        let mut key = seed_u32(seed);
+
+        for _ in 0..VAG_ROTATE_ROUNDS {
+            key = if key & 0x8000_0000 != 0 {
+                (key << 1) ^ VAG_ROTATE_XOR
+            } else {
+                key << 1
+            };
+        }
+
+        key.to_be_bytes().to_vec()
+    }
+}
+
+/// Always returns the same key regardless of the seed, mostly useful for
+/// testing with simulators or ECUs with a fixed key.
+pub struct ConstantKey {
+    /// Key sent for every seed
+    pub key: Vec<u8>,
+}
+
+impl SeedKey for ConstantKey {
+    fn compute(&self, _seed: &[u8], _level: u8) -> Vec<u8> {
+        self.key.clone()
+    }
+}
+
+/**
+ * Return the built-in algorithm with the given name, see [SEED_KEY_NAMES].
+ * The constant key is given in hex after a colon, e.g. constant:12345678.
+ */
+pub fn seed_key_by_name(name: &str) -> Result<Box<dyn SeedKey>, Error> {
+    Ok(match name {
+        "vag-add" => Box::new(VagAdd::default()),
+        "vag-rotate" => Box::new(VagRotate),
+        _ if name.starts_with("constant:") => Box::new(ConstantKey {
+            key: parse_hex_bytes(&name["constant:".len()..])?,
+        }),
+        _ => {
+            return Err(Error::new(format!(
+                "Unknown seed-key algorithm, expected one of: {}",
+                SEED_KEY_NAMES.join(", ")
+            )));
+        }
+    })
+}